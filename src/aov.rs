@@ -0,0 +1,161 @@
+use super::Config;
+use colormap::ColorMap;
+use cgmath::{Vector3, vec3};
+use film::{Depthmap, Frame, Heatmap, IdMap, Sample, ToImage, VectorMap};
+use geom::{Hit, Ray};
+use scene::Scene;
+use std::f32;
+
+/// Arbitrary output variables: the quantities that can be recorded for every primary ray.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aov {
+    Depth,
+    Heat,
+    TriangleId,
+    Barycentrics,
+    Normal,
+    Position,
+}
+
+impl Aov {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Aov::Depth => "depth",
+            Aov::Heat => "heat",
+            Aov::TriangleId => "tri-id",
+            Aov::Barycentrics => "barycentrics",
+            Aov::Normal => "normal",
+            Aov::Position => "position",
+        }
+    }
+}
+
+/// All AOVs of a single sample. They are cheap enough to always compute all of them.
+#[derive(Clone, Copy)]
+pub struct AovSample {
+    depth: f32,
+    traversal_steps: u32,
+    tri_id: Option<u32>,
+    barycentrics: Option<Vector3<f32>>,
+    /// The geometric normal of the triangle that was hit.
+    normal: Option<Vector3<f32>>,
+    position: Option<Vector3<f32>>,
+}
+
+impl AovSample {
+    pub fn new(scene: &Scene, hit: &Hit, r: &Ray) -> Self {
+        let traversal_steps = r.traversal_steps.get();
+        if !hit.is_valid() {
+            return AovSample { traversal_steps, ..AovSample::background() };
+        }
+        AovSample {
+            depth: hit.t,
+            traversal_steps,
+            tri_id: Some(scene.original_tri_id(hit)),
+            barycentrics: Some(vec3(hit.u, hit.v, hit.w)),
+            normal: Some(scene.geometric_normal(hit)),
+            position: Some(r.o + r.d * hit.t),
+        }
+    }
+
+    /// The values for samples without any ray.
+    pub fn background() -> Self {
+        AovSample {
+            depth: f32::INFINITY,
+            traversal_steps: 0,
+            tri_id: None,
+            barycentrics: None,
+            normal: None,
+            position: None,
+        }
+    }
+}
+
+type Vector = Option<Vector3<f32>>;
+
+impl Sample for AovSample {
+    type Sum = (<f32 as Sample>::Sum,
+                <u32 as Sample>::Sum,
+                <Option<u32> as Sample>::Sum,
+                <Vector as Sample>::Sum,
+                <Vector as Sample>::Sum,
+                <Vector as Sample>::Sum);
+
+    fn empty_sum() -> Self::Sum {
+        (<f32 as Sample>::empty_sum(),
+         <u32 as Sample>::empty_sum(),
+         <Option<u32> as Sample>::empty_sum(),
+         <Vector as Sample>::empty_sum(),
+         <Vector as Sample>::empty_sum(),
+         <Vector as Sample>::empty_sum())
+    }
+
+    fn add_to(self, sum: &mut Self::Sum, weight: f32) {
+        self.depth.add_to(&mut sum.0, weight);
+        self.traversal_steps.add_to(&mut sum.1, weight);
+        self.tri_id.add_to(&mut sum.2, weight);
+        self.barycentrics.add_to(&mut sum.3, weight);
+        self.normal.add_to(&mut sum.4, weight);
+        self.position.add_to(&mut sum.5, weight);
+    }
+
+    fn merge(sum: &mut Self::Sum, other: Self::Sum) {
+        <f32 as Sample>::merge(&mut sum.0, other.0);
+        <u32 as Sample>::merge(&mut sum.1, other.1);
+        <Option<u32> as Sample>::merge(&mut sum.2, other.2);
+        <Vector as Sample>::merge(&mut sum.3, other.3);
+        <Vector as Sample>::merge(&mut sum.4, other.4);
+        <Vector as Sample>::merge(&mut sum.5, other.5);
+    }
+
+    fn average(sum: Self::Sum) -> Self {
+        AovSample {
+            depth: <f32 as Sample>::average(sum.0),
+            traversal_steps: <u32 as Sample>::average(sum.1),
+            tri_id: <Option<u32> as Sample>::average(sum.2),
+            barycentrics: <Vector as Sample>::average(sum.3),
+            normal: <Vector as Sample>::average(sum.4),
+            position: <Vector as Sample>::average(sum.5),
+        }
+    }
+}
+
+/// Split a frame of AOV samples into one image per requested AOV.
+pub fn images(frame: &Frame<AovSample>, cfg: &Config) -> Vec<(&'static str, Box<ToImage>)> {
+    cfg.aovs
+        .iter()
+        .map(|&aov| {
+            let image: Box<ToImage> = match aov {
+                Aov::Depth => {
+                    Box::new(Depthmap(frame.map(|s| s.depth), cfg.style(ColorMap::Grey)))
+                }
+                Aov::Heat => {
+                    Box::new(Heatmap(frame.map(|s| s.traversal_steps), cfg.style(ColorMap::Red)))
+                }
+                Aov::TriangleId => Box::new(IdMap(frame.map(|s| s.tri_id))),
+                Aov::Barycentrics => {
+                    Box::new(VectorMap {
+                                 frame: frame.map(|s| s.barycentrics),
+                                 range: Some((0.0, 1.0)),
+                                 channel_names: ["U", "V", "W"],
+                             })
+                }
+                Aov::Normal => {
+                    Box::new(VectorMap {
+                                 frame: frame.map(|s| s.normal),
+                                 range: Some((-1.0, 1.0)),
+                                 channel_names: ["X", "Y", "Z"],
+                             })
+                }
+                Aov::Position => {
+                    Box::new(VectorMap {
+                                 frame: frame.map(|s| s.position),
+                                 range: None,
+                                 channel_names: ["X", "Y", "Z"],
+                             })
+                }
+            };
+            (aov.name(), image)
+        })
+        .collect()
+}
@@ -0,0 +1,437 @@
+use cgmath::{InnerSpace, Vector3, vec3};
+use geom::orthonormal_basis;
+use sampling::{Rng, cosine_hemisphere};
+use std::f32::consts::PI;
+
+/// An orthonormal basis around the shading normal. BSDFs work in this local coordinate system,
+/// where the normal is the Z axis. Directions always point away from the surface.
+pub struct LocalFrame {
+    t: Vector3<f32>,
+    b: Vector3<f32>,
+    n: Vector3<f32>,
+}
+
+impl LocalFrame {
+    pub fn new(n: Vector3<f32>) -> Self {
+        let (t, b) = orthonormal_basis(n);
+        LocalFrame { t, b, n }
+    }
+
+    pub fn to_local(&self, v: Vector3<f32>) -> Vector3<f32> {
+        vec3(v.dot(self.t), v.dot(self.b), v.dot(self.n))
+    }
+
+    pub fn to_world(&self, v: Vector3<f32>) -> Vector3<f32> {
+        self.t * v.x + self.b * v.y + self.n * v.z
+    }
+}
+
+/// A direction sampled from a BSDF.
+pub struct BsdfSample {
+    pub wi: Vector3<f32>,
+    /// The BSDF value times the cosine of `wi`, divided by the pdf.
+    /// This is the factor by which the path throughput changes.
+    pub weight: Vector3<f32>,
+    /// The probability density of sampling `wi`, or the probability of choosing a specular lobe.
+    pub pdf: f32,
+    /// Whether `wi` was sampled from a delta distribution, which `eval` and `pdf` can't express.
+    pub specular: bool,
+}
+
+/// Describes how light arriving from direction `wi` is scattered into direction `wo`.
+/// All directions are in the local frame of the surface.
+pub trait Bsdf {
+    /// The value of the BSDF, without the cosine term.
+    fn eval(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32>;
+    /// The probability density with which `sample` returns `wi`.
+    fn pdf(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> f32;
+    fn sample(&self, wo: Vector3<f32>, rng: &mut Rng) -> Option<BsdfSample>;
+}
+
+/// Ideal diffuse reflection, on both sides of the surface.
+pub struct Lambert {
+    pub albedo: Vector3<f32>,
+}
+
+impl Bsdf for Lambert {
+    fn eval(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32> {
+        if wo.z * wi.z > 0.0 {
+            self.albedo / PI
+        } else {
+            vec3(0.0, 0.0, 0.0)
+        }
+    }
+
+    fn pdf(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> f32 {
+        if wo.z * wi.z > 0.0 {
+            wi.z.abs() / PI
+        } else {
+            0.0
+        }
+    }
+
+    fn sample(&self, wo: Vector3<f32>, rng: &mut Rng) -> Option<BsdfSample> {
+        let mut wi = cosine_hemisphere(vec3(0.0, 0.0, 1.0), rng.next_2d());
+        if wo.z < 0.0 {
+            wi.z = -wi.z;
+        }
+        // The cosine and pi in the BSDF and the pdf cancel out.
+        Some(BsdfSample {
+                 wi,
+                 weight: self.albedo,
+                 pdf: self.pdf(wo, wi),
+                 specular: false,
+             })
+    }
+}
+
+/// A rough metal, modelled with the GGX microfacet distribution and Schlick's approximation
+/// of the Fresnel term. Reflects on both sides of the surface.
+pub struct Conductor {
+    /// Reflectance at normal incidence.
+    pub specular: Vector3<f32>,
+    pub alpha: f32,
+}
+
+impl Bsdf for Conductor {
+    fn eval(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32> {
+        let (wo, wi) = flip_to_upper(wo, wi);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return vec3(0.0, 0.0, 0.0);
+        }
+        let h = (wo + wi).normalize();
+        let d_g = ggx_d(h, self.alpha) * smith_g(wo, wi, self.alpha);
+        fresnel_schlick(wo.dot(h), self.specular) * (d_g / (4.0 * wo.z * wi.z))
+    }
+
+    fn pdf(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> f32 {
+        let (wo, wi) = flip_to_upper(wo, wi);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let h = (wo + wi).normalize();
+        ggx_d(h, self.alpha) * h.z / (4.0 * wo.dot(h))
+    }
+
+    fn sample(&self, wo: Vector3<f32>, rng: &mut Rng) -> Option<BsdfSample> {
+        let sign = wo.z.signum();
+        let wo = vec3(wo.x, wo.y, wo.z * sign);
+        let h = sample_ggx(self.alpha, rng.next_2d());
+        let cos_oh = wo.dot(h);
+        let wi = reflect(wo, h);
+        if cos_oh <= 0.0 || wi.z <= 0.0 || wo.z <= 0.0 {
+            return None;
+        }
+        // D cancels out between the BSDF and the pdf of the microfacet normal.
+        let g = smith_g(wo, wi, self.alpha);
+        Some(BsdfSample {
+                 wi: vec3(wi.x, wi.y, wi.z * sign),
+                 weight: fresnel_schlick(cos_oh, self.specular) * (g * cos_oh / (wo.z * h.z)),
+                 pdf: ggx_d(h, self.alpha) * h.z / (4.0 * cos_oh),
+                 specular: false,
+             })
+    }
+}
+
+/// A perfectly smooth interface between air and a transparent medium, such as glass.
+/// The normal points towards the outside.
+pub struct SmoothDielectric {
+    /// Index of refraction of the inside.
+    pub ior: f32,
+}
+
+impl Bsdf for SmoothDielectric {
+    fn eval(&self, _: Vector3<f32>, _: Vector3<f32>) -> Vector3<f32> {
+        vec3(0.0, 0.0, 0.0)
+    }
+
+    fn pdf(&self, _: Vector3<f32>, _: Vector3<f32>) -> f32 {
+        0.0
+    }
+
+    fn sample(&self, wo: Vector3<f32>, rng: &mut Rng) -> Option<BsdfSample> {
+        let (eta, n) = relative_ior(wo, self.ior);
+        let f = fresnel_dielectric(wo.z.abs(), eta);
+        // Reflection and refraction are chosen in proportion to their contribution.
+        let (wi, weight, pdf) = if rng.next_f32() < f {
+            (vec3(-wo.x, -wo.y, wo.z), 1.0, f)
+        } else {
+            // Radiance is compressed into a smaller solid angle when entering the denser medium.
+            match refract(wo, n, eta) {
+                Some(wi) => (wi, 1.0 / (eta * eta), 1.0 - f),
+                None => return None,
+            }
+        };
+        Some(BsdfSample {
+                 wi,
+                 weight: vec3(weight, weight, weight),
+                 pdf,
+                 specular: true,
+             })
+    }
+}
+
+/// A rough interface between air and a transparent medium, with GGX microfacets.
+/// This is the model from Walter et al., "Microfacet Models for Refraction through Rough
+/// Surfaces" (2007). The normal points towards the outside.
+pub struct RoughDielectric {
+    /// Index of refraction of the inside.
+    pub ior: f32,
+    pub alpha: f32,
+}
+
+impl RoughDielectric {
+    /// The microfacet normal (in the upper hemisphere) that scatters `wo` into `wi`.
+    /// Both directions must be flipped so that `wo` is in the upper hemisphere.
+    fn half_vector(&self, wo: Vector3<f32>, wi: Vector3<f32>, eta: f32) -> Vector3<f32> {
+        let h = if wi.z > 0.0 {
+            (wo + wi).normalize()
+        } else {
+            (wo + wi * eta).normalize()
+        };
+        if h.z < 0.0 { -h } else { h }
+    }
+}
+
+impl Bsdf for RoughDielectric {
+    fn eval(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32> {
+        let (eta, _) = relative_ior(wo, self.ior);
+        let (wo, wi) = flip_to_upper(wo, wi);
+        if wo.z == 0.0 || wi.z == 0.0 {
+            return vec3(0.0, 0.0, 0.0);
+        }
+        let h = self.half_vector(wo, wi, eta);
+        let (cos_oh, cos_ih) = (wo.dot(h), wi.dot(h));
+        let d_g = ggx_d(h, self.alpha) * smith_g(wo, wi, self.alpha);
+        let f = fresnel_dielectric(cos_oh, eta);
+        let value = if wi.z > 0.0 {
+            f * d_g / (4.0 * wo.z * wi.z)
+        } else {
+            if cos_oh <= 0.0 || cos_ih >= 0.0 {
+                return vec3(0.0, 0.0, 0.0);
+            }
+            // The factor eta^2 of the Jacobian cancels with the radiance scaling 1/eta^2.
+            let denom = cos_oh + eta * cos_ih;
+            (1.0 - f) * d_g * (cos_oh * cos_ih).abs() / (wo.z * wi.z.abs() * denom * denom)
+        };
+        vec3(value, value, value)
+    }
+
+    fn pdf(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> f32 {
+        let (eta, _) = relative_ior(wo, self.ior);
+        let (wo, wi) = flip_to_upper(wo, wi);
+        if wo.z == 0.0 || wi.z == 0.0 {
+            return 0.0;
+        }
+        let h = self.half_vector(wo, wi, eta);
+        let (cos_oh, cos_ih) = (wo.dot(h), wi.dot(h));
+        if cos_oh <= 0.0 {
+            return 0.0;
+        }
+        let pdf_h = ggx_d(h, self.alpha) * h.z;
+        let f = fresnel_dielectric(cos_oh, eta);
+        if wi.z > 0.0 {
+            f * pdf_h / (4.0 * cos_oh)
+        } else {
+            if cos_ih >= 0.0 {
+                return 0.0;
+            }
+            let denom = cos_oh + eta * cos_ih;
+            (1.0 - f) * pdf_h * eta * eta * cos_ih.abs() / (denom * denom)
+        }
+    }
+
+    fn sample(&self, wo: Vector3<f32>, rng: &mut Rng) -> Option<BsdfSample> {
+        let (eta, _) = relative_ior(wo, self.ior);
+        let sign = wo.z.signum();
+        let wo_up = vec3(wo.x, wo.y, wo.z * sign);
+        let h = sample_ggx(self.alpha, rng.next_2d());
+        let cos_oh = wo_up.dot(h);
+        if cos_oh <= 0.0 {
+            return None;
+        }
+        let f = fresnel_dielectric(cos_oh, eta);
+        let wi_up = if rng.next_f32() < f {
+            reflect(wo_up, h)
+        } else {
+            match refract(wo_up, h, eta) {
+                Some(wi) => wi,
+                None => return None,
+            }
+        };
+        let wi = vec3(wi_up.x, wi_up.y, wi_up.z * sign);
+        let pdf = self.pdf(wo, wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+                 wi,
+                 weight: self.eval(wo, wi) * (wi.z.abs() / pdf),
+                 pdf,
+                 specular: false,
+             })
+    }
+}
+
+/// Mirror both directions at the surface if necessary, so that `wo` is in the upper hemisphere.
+fn flip_to_upper(wo: Vector3<f32>, wi: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    if wo.z < 0.0 {
+        (vec3(wo.x, wo.y, -wo.z), vec3(wi.x, wi.y, -wi.z))
+    } else {
+        (wo, wi)
+    }
+}
+
+/// The ratio of the index of refraction on the other side of the surface to that on the side
+/// of `wo`, and the normal on the side of `wo`.
+fn relative_ior(wo: Vector3<f32>, ior: f32) -> (f32, Vector3<f32>) {
+    if wo.z >= 0.0 {
+        (ior, vec3(0.0, 0.0, 1.0))
+    } else {
+        (1.0 / ior, vec3(0.0, 0.0, -1.0))
+    }
+}
+
+fn reflect(wo: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
+    n * (2.0 * wo.dot(n)) - wo
+}
+
+/// Refract `wo` through a surface with normal `n` (on the side of `wo`), where `eta` is the
+/// relative index of refraction. Returns `None` on total internal reflection.
+fn refract(wo: Vector3<f32>, n: Vector3<f32>, eta: f32) -> Option<Vector3<f32>> {
+    let cos_i = wo.dot(n);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + n * (cos_i / eta - cos_t))
+}
+
+/// The Fresnel reflectance of unpolarized light at a dielectric interface, where `cos_i` is the
+/// (positive) cosine of the incident direction and `eta` is the relative index of refraction.
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_s = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let r_p = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    (r_s * r_s + r_p * r_p) / 2.0
+}
+
+fn fresnel_schlick(cos_i: f32, f0: Vector3<f32>) -> Vector3<f32> {
+    let m = (1.0 - cos_i).max(0.0).min(1.0);
+    f0 + (vec3(1.0, 1.0, 1.0) - f0) * m.powi(5)
+}
+
+/// The GGX distribution of microfacet normals.
+fn ggx_d(h: Vector3<f32>, alpha: f32) -> f32 {
+    if h.z <= 0.0 {
+        return 0.0;
+    }
+    let cos2 = h.z * h.z;
+    let tan2 = (1.0 - cos2) / cos2;
+    let a2 = alpha * alpha;
+    a2 / (PI * cos2 * cos2 * (a2 + tan2) * (a2 + tan2))
+}
+
+/// Smith's masking function for GGX, for a single direction.
+fn smith_g1(v: Vector3<f32>, alpha: f32) -> f32 {
+    let cos2 = v.z * v.z;
+    let tan2 = (1.0 - cos2).max(0.0) / cos2;
+    2.0 / (1.0 + (1.0 + alpha * alpha * tan2).sqrt())
+}
+
+/// Smith's shadowing-masking function, assuming independent masking and shadowing.
+fn smith_g(wo: Vector3<f32>, wi: Vector3<f32>, alpha: f32) -> f32 {
+    smith_g1(wo, alpha) * smith_g1(wi, alpha)
+}
+
+/// Sample a microfacet normal with density D(h) * cos(theta_h).
+fn sample_ggx(alpha: f32, (u, v): (f32, f32)) -> Vector3<f32> {
+    let tan2 = alpha * alpha * u / (1.0 - u);
+    let cos_theta = 1.0 / (1.0 + tan2).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32, what: &str) {
+        assert!((a - b).abs() <= 1e-3 * (a.abs() + b.abs()) + 1e-6,
+                "{}: {} != {}",
+                what,
+                a,
+                b);
+    }
+
+    /// Check that the pdf and weight returned by `sample` agree with `pdf` and `eval`.
+    fn check_consistency(bsdf: &Bsdf) {
+        let wos = [vec3(0.0, 0.0, 1.0),
+                   vec3(0.3, -0.2, 0.5).normalize(),
+                   vec3(-0.8, 0.1, 0.2).normalize(),
+                   vec3(0.1, 0.4, -0.7).normalize(),
+                   vec3(0.6, 0.0, -0.1).normalize()];
+        let mut rng = Rng::new(1, 2);
+        let mut samples = 0;
+        for &wo in &wos {
+            for _ in 0..1000 {
+                let s = match bsdf.sample(wo, &mut rng) {
+                    Some(s) => s,
+                    None => continue,
+                };
+                samples += 1;
+                assert!(s.pdf > 0.0 && s.pdf.is_finite(), "pdf {} for {:?}", s.pdf, s.wi);
+                assert!(s.weight.x.is_finite() && s.weight.y.is_finite() &&
+                        s.weight.z.is_finite(),
+                        "weight {:?} for {:?}",
+                        s.weight,
+                        s.wi);
+                assert_close(s.wi.magnitude(), 1.0, "length of wi");
+                if s.specular {
+                    // A delta lobe is chosen with a discrete probability.
+                    assert!(s.pdf <= 1.0, "pdf {} of a specular sample", s.pdf);
+                    continue;
+                }
+                assert_close(s.pdf, bsdf.pdf(wo, s.wi), "pdf");
+                let expected = bsdf.eval(wo, s.wi) * (s.wi.z.abs() / s.pdf);
+                assert_close(s.weight.x, expected.x, "weight.x");
+                assert_close(s.weight.y, expected.y, "weight.y");
+                assert_close(s.weight.z, expected.z, "weight.z");
+            }
+        }
+        assert!(samples > 0);
+    }
+
+    #[test]
+    fn lambert_sample_is_consistent() {
+        check_consistency(&Lambert { albedo: vec3(0.8, 0.5, 0.2) });
+    }
+
+    #[test]
+    fn conductor_sample_is_consistent() {
+        for &alpha in &[0.1, 0.5, 1.0] {
+            check_consistency(&Conductor {
+                                   specular: vec3(0.9, 0.6, 0.3),
+                                   alpha,
+                               });
+        }
+    }
+
+    #[test]
+    fn smooth_dielectric_sample_is_consistent() {
+        check_consistency(&SmoothDielectric { ior: 1.5 });
+    }
+
+    #[test]
+    fn rough_dielectric_sample_is_consistent() {
+        for &alpha in &[0.1, 0.5, 1.0] {
+            check_consistency(&RoughDielectric { ior: 1.5, alpha });
+        }
+    }
+}
@@ -4,12 +4,13 @@ use beebox::{self, Aabb};
 use beevage::{self, Axis};
 use cast::{u32, usize};
 use geom::{Hit, Ray, Tri, TriSliceExt};
-use rayon::prelude::*;
 use std::u32;
 use watertri;
 
 pub struct Bvh {
     nodes: Box<[CompactNode]>,
+    /// The offset of the first primitive of each leaf, in increasing order.
+    leaf_starts: Box<[u32]>,
 }
 
 const LEAF_OR_NODE_MASK: u32 = 1 << 31;
@@ -69,7 +70,27 @@ impl Bvh {
         assert_eq!(nodes.len(),
                    node_count,
                    "Builder reported wrong number of nodes");
-        Bvh { nodes: nodes.into_boxed_slice() }
+        // Leaves are laid out depth-first, so their primitive ranges are already sorted.
+        let leaf_starts = nodes.iter()
+            .filter_map(|node| match node.unpack() {
+                            UnpackedNode::Leaf { start, .. } => Some(start),
+                            UnpackedNode::Interior { .. } => None,
+                        })
+            .collect::<Vec<_>>();
+        debug_assert!(leaf_starts.windows(2).all(|w| w[0] <= w[1]));
+        Bvh {
+            nodes: nodes.into_boxed_slice(),
+            leaf_starts: leaf_starts.into_boxed_slice(),
+        }
+    }
+
+    /// The index of the leaf containing the primitive `tri_id`, counting leaves depth-first.
+    pub fn leaf_id(&self, tri_id: u32) -> u32 {
+        let i = match self.leaf_starts.binary_search(&tri_id) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        u32(i).unwrap()
     }
 }
 
@@ -109,7 +130,9 @@ fn compactify(nodes: &mut Vec<CompactNode>, node: beevage::Node) -> NodeId {
 
 const MAX_DEPTH: usize = 64;
 
-pub fn construct(tris: &[Tri], cfg: &Config) -> (Bvh, Vec<Tri>) {
+/// Build a BVH over `tris`. The BVH refers to the triangles in a different order, which is
+/// returned as the original index of every triangle in BVH order.
+pub fn construct(tris: &[Tri], cfg: &Config) -> (Bvh, Vec<usize>) {
     let msg = format!("building BVH for {} tris", tris.len());
     print_timing(&msg, move || {
         let bb = tris.bbox();
@@ -119,8 +142,8 @@ pub fn construct(tris: &[Tri], cfg: &Config) -> (Bvh, Vec<Tri>) {
             max_depth: MAX_DEPTH,
         };
         let beevage::Bvh { root, node_count, primitives } = beevage::binned_sah(config, tris, bb);
-        let bvh_tris = primitives.into_par_iter().map(|p| tris[p.index()].clone()).collect();
-        (Bvh::compactify(root, node_count), bvh_tris)
+        let order = primitives.into_iter().map(|p| p.index()).collect();
+        (Bvh::compactify(root, node_count), order)
     })
 }
 
@@ -141,7 +164,7 @@ pub fn traverse(tris: &[Tri], tree: &Bvh, r: &Ray) -> Hit {
     while let Some(id) = todo.pop() {
         r.traversal_steps.set(r.traversal_steps.get() + 1);
         let node = &tree.nodes[id.to_index()];
-        if !node.bb.intersects(&r_box, 0.0, r.t_max.get()) {
+        if !node.bb.intersects(&r_box, r.t_min, r.t_max.get()) {
             continue;
         }
         match node.unpack() {
@@ -161,3 +184,38 @@ pub fn traverse(tris: &[Tri], tree: &Bvh, r: &Ray) -> Hit {
     }
     hit
 }
+
+/// Like `traverse`, but only determine whether there is any hit between `r.t_min` and `r.t_max`.
+/// This stops at the first intersection found, so it's cheaper than finding the closest hit.
+pub fn occluded(tris: &[Tri], tree: &Bvh, r: &Ray) -> bool {
+    let r_tri = watertri::RayData::new(r.o, r.d);
+    let r_box = beebox::RayData::new(r.o, r.d);
+
+    let mut todo = ArrayVec::<[_; MAX_DEPTH]>::new();
+    todo.push(NodeId(0));
+    while let Some(id) = todo.pop() {
+        r.traversal_steps.set(r.traversal_steps.get() + 1);
+        let node = &tree.nodes[id.to_index()];
+        if !node.bb.intersects(&r_box, r.t_min, r.t_max.get()) {
+            continue;
+        }
+        match node.unpack() {
+            UnpackedNode::Leaf { start, end } => {
+                if tris[usize(start)..usize(end)].intersects_any(r, &r_tri) {
+                    return true;
+                }
+            }
+            UnpackedNode::Interior { second_child, axis } => {
+                // Any hit will do, but visiting the near child first tends to find one sooner.
+                if r.d[usize(axis)] < 0.0 {
+                    todo.push(id.left_child());
+                    todo.push(second_child);
+                } else {
+                    todo.push(second_child);
+                    todo.push(id.left_child());
+                }
+            }
+        }
+    }
+    false
+}
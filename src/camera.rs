@@ -0,0 +1,187 @@
+use super::Config;
+use beebox::Aabb;
+use cast::f32;
+use cgmath::{InnerSpace, Vector3, vec3};
+use geom::Ray;
+use sampling::concentric_disk;
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::process;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Projection {
+    Perspective,
+    /// All rays are parallel to the view direction.
+    Orthographic,
+    /// 360° panorama, longitude maps to x and latitude to y.
+    Equirectangular,
+    /// Angular fisheye, the distance from the image center is proportional to the angle
+    /// from the view direction. Pixels outside the image circle don't get any rays.
+    Fisheye,
+}
+
+/// A look-at camera.
+/// With a non-zero lens radius, it models a thin lens that is focused on a plane
+/// `focus_distance` units in front of the eye.
+pub struct Camera {
+    projection: Projection,
+    eye: Vector3<f32>,
+    /// Orthonormal camera basis: `right` and `up` span the image plane,
+    /// `forward` points from the eye towards the target.
+    right: Vector3<f32>,
+    up: Vector3<f32>,
+    forward: Vector3<f32>,
+    /// Half the extent of the image, in units that depend on the projection:
+    /// - perspective: extent of the image plane at unit distance from the eye
+    /// - orthographic: extent of the image plane in world units
+    /// - equirectangular: longitude and latitude in radians
+    /// - fisheye: angle from the view direction in radians
+    half_width: f32,
+    half_height: f32,
+    lens_radius: f32,
+    focus_distance: f32,
+    image_width: u32,
+    image_height: u32,
+}
+
+impl Camera {
+    pub fn new(cfg: &Config, scene_bb: &Aabb) -> Self {
+        let eye = cfg.camera_eye.unwrap_or_else(|| default_eye(scene_bb));
+        let target = cfg.camera_target.unwrap_or_else(|| default_target(eye));
+        // The command line is checked where possible, but the default eye depends on the model.
+        let (right, up, forward) = match view_basis(eye, target, cfg.camera_up) {
+            Ok(basis) => basis,
+            Err(msg) => {
+                println!("invalid camera: {}", msg);
+                process::exit(1);
+            }
+        };
+
+        let aspect_ratio = f32(cfg.image_width) / f32(cfg.image_height);
+        let (half_width, half_height) = match (cfg.projection, cfg.camera_fov) {
+            (Projection::Perspective, Some(fov)) => {
+                let half_height = (fov.to_radians() / 2.0).tan();
+                (aspect_ratio * half_height, half_height)
+            }
+            // This is the framing we used before the camera was configurable.
+            // It doesn't preserve the pixel aspect ratio, but keeps old renders comparable.
+            (Projection::Perspective, None) => (aspect_ratio / 2.0, aspect_ratio / 2.0),
+            (Projection::Orthographic, _) => {
+                let height = cfg.ortho_height.unwrap_or(scene_bb.max().y - scene_bb.min().y);
+                (aspect_ratio * height / 2.0, height / 2.0)
+            }
+            (Projection::Equirectangular, _) => (PI, FRAC_PI_2),
+            (Projection::Fisheye, fov) => {
+                // The image circle touches the shorter sides of the image.
+                let half_fov = fov.unwrap_or(180.0).to_radians() / 2.0;
+                if aspect_ratio >= 1.0 {
+                    (aspect_ratio * half_fov, half_fov)
+                } else {
+                    (half_fov, half_fov / aspect_ratio)
+                }
+            }
+        };
+        // By default, focus on the center of the model, unless it's behind the camera.
+        let center = (scene_bb.min() + scene_bb.max()) / 2.0;
+        let focus_distance = cfg.focus_distance.unwrap_or_else(|| {
+            let dist = (center - eye).dot(forward);
+            if dist > 0.0 { dist } else { 1.0 }
+        });
+        Camera {
+            projection: cfg.projection,
+            eye,
+            right,
+            up,
+            forward,
+            half_width,
+            half_height,
+            lens_radius: cfg.lens_radius,
+            focus_distance,
+            image_width: cfg.image_width,
+            image_height: cfg.image_height,
+        }
+    }
+
+    /// Generate a ray through pixel (x, y), if there is one.
+    /// `pixel_sample` is the position within the pixel, in the unit square.
+    /// `lens_sample` is a point in the unit square that selects the point on the lens
+    /// the ray passes through. It is irrelevant if the lens radius is zero.
+    /// The panoramic projections are always pinhole cameras.
+    pub fn primary_ray(&self,
+                       x: u32,
+                       y: u32,
+                       pixel_sample: (f32, f32),
+                       lens_sample: (f32, f32))
+                       -> Option<Ray> {
+        let norm_x = (f32(x) + pixel_sample.0) / f32(self.image_width);
+        let norm_y = (f32(y) + pixel_sample.1) / f32(self.image_height);
+        let cam_x = self.half_width * (2.0 * norm_x - 1.0);
+        let cam_y = self.half_height * (1.0 - 2.0 * norm_y);
+        // The pinhole ray, with the direction scaled to unit length along the view direction.
+        let (o, d) = match self.projection {
+            Projection::Perspective => {
+                (self.eye, self.right * cam_x + self.up * cam_y + self.forward)
+            }
+            Projection::Orthographic => {
+                (self.eye + self.right * cam_x + self.up * cam_y, self.forward)
+            }
+            Projection::Equirectangular => {
+                let (longitude, latitude) = (cam_x, cam_y);
+                let d = self.forward * (latitude.cos() * longitude.cos()) +
+                        self.right * (latitude.cos() * longitude.sin()) +
+                        self.up * latitude.sin();
+                return Some(Ray::new(self.eye, d));
+            }
+            Projection::Fisheye => {
+                let theta = (cam_x * cam_x + cam_y * cam_y).sqrt();
+                if theta > self.half_width.min(self.half_height) {
+                    return None;
+                }
+                let phi = cam_y.atan2(cam_x);
+                let d = self.forward * theta.cos() +
+                        (self.right * phi.cos() + self.up * phi.sin()) * theta.sin();
+                return Some(Ray::new(self.eye, d));
+            }
+        };
+        if self.lens_radius == 0.0 {
+            return Some(Ray::new(o, d.normalize()));
+        }
+        // All rays through the lens that start at the same pixel converge on the focus plane.
+        let focus_point = o + d * self.focus_distance;
+        let (lens_x, lens_y) = concentric_disk(lens_sample);
+        let lens_o = o + self.right * (lens_x * self.lens_radius) +
+                     self.up * (lens_y * self.lens_radius);
+        Some(Ray::new(lens_o, (focus_point - lens_o).normalize()))
+    }
+}
+
+/// Eye position used when the user doesn't specify it.
+/// This heuristically places the camera such that the model is probably within view:
+/// in front of the center of the bounding box.
+fn default_eye(bb: &Aabb) -> Vector3<f32> {
+    let (min, max) = (bb.min(), bb.max());
+    let center = (min + max) / 2.0;
+    center + vec3(0.0, 0.0, (min.z - max.z).abs())
+}
+
+/// Target used when the user doesn't specify it: straight down the negative Z axis.
+pub fn default_target(eye: Vector3<f32>) -> Vector3<f32> {
+    eye - vec3(0.0, 0.0, 1.0)
+}
+
+/// The orthonormal camera basis `(right, up, forward)` for looking from `eye` at `target`.
+/// Fails if the view direction is undefined or parallel to `up`.
+pub fn view_basis(eye: Vector3<f32>,
+                  target: Vector3<f32>,
+                  up: Vector3<f32>)
+                  -> Result<(Vector3<f32>, Vector3<f32>, Vector3<f32>), String> {
+    if eye == target {
+        return Err("the eye must not be at the target".to_string());
+    }
+    let forward = (target - eye).normalize();
+    let right = forward.cross(up);
+    if !(right.magnitude2() > 0.0) {
+        return Err("the up vector must not be parallel to the view direction".to_string());
+    }
+    let right = right.normalize();
+    Ok((right, right.cross(forward), forward))
+}
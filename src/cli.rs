@@ -1,13 +1,27 @@
 use super::{Config, RenderKind};
-use clap::{Arg, ArgMatches, App};
+use aov::Aov;
+use camera::{self, Projection};
+use cgmath::{Vector3, vec3};
+use clap::{self, Arg, ArgMatches, App};
+use colormap::{ColorMap, Scale};
+use filter::Filter;
+use light::{self, DeltaLight};
+use output;
 use regex::Regex;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use texture::Wrap;
 
 lazy_static! {
     static ref IMG_DIM_REGEX: Regex = Regex::new("^([:digit:]+)x([:digit:]+)$").unwrap();
-    static ref POSITIVE_INT_REGEX: Regex = Regex::new("^[:digit:]+$").unwrap();
-    static ref POSITIVE_FLOAT_REGEX: Regex = Regex::new(r"^[:digit:]+\.[:digit:]+$").unwrap();
+    static ref NON_NEGATIVE_INT_REGEX: Regex = Regex::new("^[:digit:]+$").unwrap();
+    static ref POSITIVE_INT_REGEX: Regex = Regex::new("^0*[1-9][:digit:]*$").unwrap();
+    static ref NON_NEGATIVE_FLOAT_REGEX: Regex =
+        Regex::new(r"^[:digit:]+(?:\.[:digit:]+)?$").unwrap();
+    static ref VEC3_REGEX: Regex = {
+        let float = r"(-?[:digit:]+(?:\.[:digit:]+)?)";
+        Regex::new(&format!("^{0},{0},{0}$", float)).unwrap()
+    };
 }
 
 fn is_img_dim(s: String) -> Result<(), String> {
@@ -26,11 +40,66 @@ fn is_positive_int(s: String) -> Result<(), String> {
     }
 }
 
+fn is_non_negative_int(s: String) -> Result<(), String> {
+    if NON_NEGATIVE_INT_REGEX.is_match(&s) {
+        Ok(())
+    } else {
+        Err("Value must be a non-negative integer".to_string())
+    }
+}
+
+fn is_non_negative_float(s: String) -> Result<(), String> {
+    if NON_NEGATIVE_FLOAT_REGEX.is_match(&s) {
+        Ok(())
+    } else {
+        Err("Value must be a non-negative number of the form 12 or 12.34".to_string())
+    }
+}
+
 fn is_positive_float(s: String) -> Result<(), String> {
-    if POSITIVE_FLOAT_REGEX.is_match(&s) {
+    match s.parse::<f64>() {
+        Ok(x) if NON_NEGATIVE_FLOAT_REGEX.is_match(&s) && x > 0.0 => Ok(()),
+        _ => Err("Value must be a positive number of the form 12 or 12.34".to_string()),
+    }
+}
+
+fn is_clip_percentile(s: String) -> Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(p) if NON_NEGATIVE_FLOAT_REGEX.is_match(&s) && p < 50.0 => Ok(()),
+        _ => Err("Value must be a percentage of at least 0 and less than 50".to_string()),
+    }
+}
+
+fn is_vec3(s: String) -> Result<(), String> {
+    if VEC3_REGEX.is_match(&s) {
+        Ok(())
+    } else {
+        Err("Value must be 'X,Y,Z' where X, Y and Z are numbers of the form -12.34".to_string())
+    }
+}
+
+fn is_env_map_file(s: String) -> Result<(), String> {
+    let ext = Path::new(&s).extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase());
+    match ext.as_ref().map(|ext| &ext[..]) {
+        Some("hdr") | Some("pfm") => Ok(()),
+        _ => Err("File extension must be one of: hdr, pfm".to_string()),
+    }
+}
+
+fn is_light(s: String) -> Result<(), String> {
+    s.parse::<DeltaLight>().map(|_| ())
+}
+
+/// Parse the whole lights file up front, so errors are reported before the scene is loaded.
+fn is_lights_file(s: String) -> Result<(), String> {
+    light::read_lights_file(Path::new(&s)).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn is_image_file(s: String) -> Result<(), String> {
+    if output::Format::from_path(Path::new(&s)).is_some() {
         Ok(())
     } else {
-        Err("Value must be a positive number of the form 12.34".to_string())
+        Err("File extension must be one of: bmp, png, pfm, exr".to_string())
     }
 }
 
@@ -56,15 +125,16 @@ pub fn build_app() -> App<'static, 'static> {
         .arg(Arg::with_name("output")
                  .short("o")
                  .long("out")
-                 .help("File name for output")
+                 .help("File name for output, the extension determines the format")
                  .value_name("FILE")
-                 .required(false))
+                 .required(false)
+                 .validator(is_image_file))
         .arg(Arg::with_name("sah-traversal-cost")
                  .long("sah-tcost")
                  .help("Relative cost of BVH traversal step compared to triangle intersection")
                  .value_name("COST")
                  .default_value("1.0")
-                 .validator(is_positive_float))
+                 .validator(is_non_negative_float))
         .arg(Arg::with_name("input")
                  .help("OBJ file to render")
                  .value_name("FILE")
@@ -81,7 +151,191 @@ pub fn build_app() -> App<'static, 'static> {
                  .long("kind")
                  .help("Kind of render to create")
                  .default_value("depth")
-                 .possible_values(&["depth", "heat"]))
+                 .possible_values(&["depth",
+                                    "heat",
+                                    "heat-diff",
+                                    "aov",
+                                    "normal",
+                                    "facing-ratio",
+                                    "headlight",
+                                    "tri-id",
+                                    "leaf-id",
+                                    "ao",
+                                    "path"]))
+        .arg(Arg::with_name("aovs")
+                 .long("aovs")
+                 .help("Output variables to record in a single pass with --kind aov")
+                 .value_name("AOV,...")
+                 .use_delimiter(true)
+                 .default_value("depth,heat")
+                 .possible_values(&["depth",
+                                    "heat",
+                                    "tri-id",
+                                    "barycentrics",
+                                    "normal",
+                                    "position"]))
+        .arg(Arg::with_name("camera-eye")
+                 .long("eye")
+                 .help("Position of the camera (default: in front of the model)")
+                 .value_name("X,Y,Z")
+                 .required(false)
+                 .validator(is_vec3))
+        .arg(Arg::with_name("camera-target")
+                 .long("target")
+                 .help("Point the camera looks at \
+                        (default: straight down the -Z axis from the eye)")
+                 .value_name("X,Y,Z")
+                 .required(false)
+                 .validator(is_vec3))
+        .arg(Arg::with_name("camera-up")
+                 .long("up")
+                 .help("Up direction of the camera")
+                 .value_name("X,Y,Z")
+                 .default_value("0,1,0")
+                 .validator(is_vec3))
+        .arg(Arg::with_name("camera-fov")
+                 .long("fov")
+                 .help("Vertical field of view in degrees, less than 180 \
+                        (default: derived from aspect ratio) \
+                        or diameter of the fisheye image circle (default: 180)")
+                 .value_name("DEGREES")
+                 .required(false)
+                 .validator(is_positive_float))
+        .arg(Arg::with_name("projection")
+                 .short("p")
+                 .long("projection")
+                 .help("Kind of camera projection")
+                 .default_value("perspective")
+                 .possible_values(&["perspective", "ortho", "equirect", "fisheye"]))
+        .arg(Arg::with_name("ortho-height")
+                 .long("ortho-height")
+                 .help("Height of the orthographic view in world units (default: model height)")
+                 .value_name("SIZE")
+                 .required(false)
+                 .validator(is_positive_float))
+        .arg(Arg::with_name("lens-radius")
+                 .long("aperture")
+                 .help("Radius of the camera lens in world units, enables depth of field")
+                 .value_name("RADIUS")
+                 .default_value("0.0")
+                 .validator(is_non_negative_float))
+        .arg(Arg::with_name("focus-distance")
+                 .long("focus-dist")
+                 .help("Distance of the focus plane from the camera (default: model center)")
+                 .value_name("DIST")
+                 .required(false)
+                 .validator(is_positive_float))
+        .arg(Arg::with_name("samples-per-pixel")
+                 .long("spp")
+                 .help("Number of stratified samples to average per pixel")
+                 .value_name("N")
+                 .default_value("1")
+                 .validator(is_positive_int))
+        .arg(Arg::with_name("seed")
+                 .long("seed")
+                 .help("Seed for the random numbers used in sampling")
+                 .value_name("N")
+                 .default_value("0")
+                 .validator(is_non_negative_int))
+        .arg(Arg::with_name("filter")
+                 .long("filter")
+                 .help("Reconstruction filter for combining samples into pixels")
+                 .default_value("box")
+                 .possible_values(&["box", "tent", "gaussian", "mitchell", "lanczos"]))
+        .arg(Arg::with_name("colormap")
+                 .long("colormap")
+                 .help("Color map for depth and heat maps (default: grey and red, respectively)")
+                 .required(false)
+                 .possible_values(&["grey", "red", "viridis", "inferno", "turbo", "blue-red"]))
+        .arg(Arg::with_name("scale")
+                 .long("scale")
+                 .help("How values are mapped to colors")
+                 .default_value("linear")
+                 .possible_values(&["linear", "log", "percentile"]))
+        .arg(Arg::with_name("clip-percentile")
+                 .long("clip")
+                 .help("Percentage of values clipped at either end with --scale percentile, \
+                        less than 50")
+                 .value_name("PERCENT")
+                 .default_value("1.0")
+                 .validator(is_clip_percentile))
+        .arg(Arg::with_name("legend")
+                 .long("legend")
+                 .help("Draw a color bar with the value range into the image"))
+        .arg(Arg::with_name("heat-max")
+                 .long("heat-max")
+                 .help("Fix the heat scale to 0..N traversal steps, to compare different renders")
+                 .value_name("N")
+                 .required(false)
+                 .validator(is_positive_int))
+        .arg(Arg::with_name("diff-sah-buckets")
+                 .long("diff-buckets")
+                 .help("Number of SAH buckets for the second BVH with --kind heat-diff \
+                        (default: same as --buckets)")
+                 .value_name("N")
+                 .required(false)
+                 .validator(is_positive_int))
+        .arg(Arg::with_name("diff-sah-traversal-cost")
+                 .long("diff-tcost")
+                 .help("SAH traversal cost for the second BVH with --kind heat-diff \
+                        (default: same as --sah-tcost)")
+                 .value_name("COST")
+                 .required(false)
+                 .validator(is_non_negative_float))
+        .arg(Arg::with_name("ao-samples")
+                 .long("ao-samples")
+                 .help("Number of occlusion rays per primary hit with --kind ao")
+                 .value_name("N")
+                 .default_value("16")
+                 .validator(is_positive_int))
+        .arg(Arg::with_name("ao-distance")
+                 .long("ao-distance")
+                 .help("Maximum distance of occluders with --kind ao \
+                        (default: a tenth of the model's diagonal)")
+                 .value_name("DIST")
+                 .required(false)
+                 .validator(is_positive_float))
+        .arg(Arg::with_name("max-bounces")
+                 .long("max-bounces")
+                 .help("Maximum number of bounces of a path with --kind path")
+                 .value_name("N")
+                 .default_value("8")
+                 .validator(is_positive_int))
+        .arg(Arg::with_name("sky-color")
+                 .long("sky")
+                 .help("Radiance of the constant sky with --kind path, ignored with --env")
+                 .value_name("R,G,B")
+                 .default_value("1,1,1")
+                 .validator(is_vec3))
+        .arg(Arg::with_name("texture-wrap")
+                 .long("texture-wrap")
+                 .help("How textures are continued outside of the [0, 1] coordinate range")
+                 .default_value("repeat")
+                 .possible_values(&["repeat", "clamp", "mirror"]))
+        .arg(Arg::with_name("env-map")
+                 .long("env")
+                 .help("Equirectangular HDR image lighting the scene from all directions, \
+                        takes precedence over --sky and is shown as background by the other \
+                        render kinds")
+                 .value_name("FILE")
+                 .required(false)
+                 .validator(is_env_map_file))
+        .arg(Arg::with_name("light")
+                 .long("light")
+                 .help("Add a light with --kind path (can be repeated): \
+                        'point POS INTENSITY', 'spot POS DIR INTENSITY ANGLE [INNER_ANGLE]' \
+                        or 'directional DIR IRRADIANCE', with angles in degrees")
+                 .value_name("LIGHT")
+                 .multiple(true)
+                 .number_of_values(1)
+                 .required(false)
+                 .validator(is_light))
+        .arg(Arg::with_name("lights-file")
+                 .long("lights")
+                 .help("File with one light per line, written as for --light")
+                 .value_name("FILE")
+                 .required(false)
+                 .validator(is_lights_file))
 }
 
 pub fn parse_matches(matches: ArgMatches) -> Config {
@@ -89,6 +343,15 @@ pub fn parse_matches(matches: ArgMatches) -> Config {
         matches.value_of(key).and_then(|s| s.parse().ok())
     }
 
+    fn parse_vec3(matches: &ArgMatches, key: &str) -> Option<Vector3<f32>> {
+        matches.value_of(key).map(|s| {
+            let captures = VEC3_REGEX.captures(s).unwrap();
+            vec3(captures[1].parse().unwrap(),
+                 captures[2].parse().unwrap(),
+                 captures[3].parse().unwrap())
+        })
+    }
+
     let input_file = matches.value_of_os("input").map(PathBuf::from).unwrap();
     let output_file = matches.value_of_os("output")
         .map(PathBuf::from)
@@ -96,7 +359,7 @@ pub fn parse_matches(matches: ArgMatches) -> Config {
 
     let dim = matches.value_of("dimensions").unwrap();
     let dim_captures = IMG_DIM_REGEX.captures(dim).unwrap();
-    Config {
+    let cfg = Config {
         input_file,
         output_file,
         image_width: dim_captures[1].parse().unwrap(),
@@ -107,7 +370,113 @@ pub fn parse_matches(matches: ArgMatches) -> Config {
         render_kind: match matches.value_of("render-kind") {
             Some("depth") => RenderKind::Depthmap,
             Some("heat") => RenderKind::Heatmap,
+            Some("heat-diff") => RenderKind::HeatDiff,
+            Some("aov") => RenderKind::Aovs,
+            Some("normal") => RenderKind::Normals,
+            Some("facing-ratio") => RenderKind::FacingRatio,
+            Some("headlight") => RenderKind::Headlight,
+            Some("tri-id") => RenderKind::TriangleIds,
+            Some("leaf-id") => RenderKind::LeafIds,
+            Some("ao") => RenderKind::AmbientOcclusion,
+            Some("path") => RenderKind::PathTrace,
             other => panic!("BUG: unhandled render-kind {:?}", other),
         },
+        camera_eye: parse_vec3(&matches, "camera-eye"),
+        camera_target: parse_vec3(&matches, "camera-target"),
+        camera_up: parse_vec3(&matches, "camera-up").unwrap(),
+        camera_fov: parse_arg(&matches, "camera-fov"),
+        projection: match matches.value_of("projection") {
+            Some("perspective") => Projection::Perspective,
+            Some("ortho") => Projection::Orthographic,
+            Some("equirect") => Projection::Equirectangular,
+            Some("fisheye") => Projection::Fisheye,
+            other => panic!("BUG: unhandled projection {:?}", other),
+        },
+        ortho_height: parse_arg(&matches, "ortho-height"),
+        lens_radius: parse_arg(&matches, "lens-radius").unwrap(),
+        focus_distance: parse_arg(&matches, "focus-distance"),
+        samples_per_pixel: parse_arg(&matches, "samples-per-pixel").unwrap(),
+        seed: parse_arg(&matches, "seed").unwrap(),
+        filter: match matches.value_of("filter") {
+            Some("box") => Filter::Box,
+            Some("tent") => Filter::Tent,
+            Some("gaussian") => Filter::Gaussian,
+            Some("mitchell") => Filter::Mitchell,
+            Some("lanczos") => Filter::Lanczos,
+            other => panic!("BUG: unhandled filter {:?}", other),
+        },
+        aovs: matches.values_of("aovs")
+            .unwrap()
+            .map(|aov| match aov {
+                     "depth" => Aov::Depth,
+                     "heat" => Aov::Heat,
+                     "tri-id" => Aov::TriangleId,
+                     "barycentrics" => Aov::Barycentrics,
+                     "normal" => Aov::Normal,
+                     "position" => Aov::Position,
+                     other => panic!("BUG: unhandled AOV {:?}", other),
+                 })
+            .collect(),
+        colormap: matches.value_of("colormap").map(|colormap| match colormap {
+            "grey" => ColorMap::Grey,
+            "red" => ColorMap::Red,
+            "viridis" => ColorMap::Viridis,
+            "inferno" => ColorMap::Inferno,
+            "turbo" => ColorMap::Turbo,
+            "blue-red" => ColorMap::BlueRed,
+            other => panic!("BUG: unhandled colormap {:?}", other),
+        }),
+        scale: match matches.value_of("scale") {
+            Some("linear") => Scale::Linear,
+            Some("log") => Scale::Log,
+            Some("percentile") => {
+                Scale::Percentile(parse_arg(&matches, "clip-percentile").unwrap())
+            }
+            other => panic!("BUG: unhandled scale {:?}", other),
+        },
+        legend: matches.is_present("legend"),
+        heat_max: parse_arg(&matches, "heat-max"),
+        diff_sah_buckets: parse_arg(&matches, "diff-sah-buckets")
+            .or(parse_arg(&matches, "sah-buckets"))
+            .unwrap(),
+        diff_sah_traversal_cost: parse_arg(&matches, "diff-sah-traversal-cost")
+            .or(parse_arg(&matches, "sah-traversal-cost"))
+            .unwrap(),
+        ao_samples: parse_arg(&matches, "ao-samples").unwrap(),
+        ao_distance: parse_arg(&matches, "ao-distance"),
+        max_bounces: parse_arg(&matches, "max-bounces").unwrap(),
+        sky_color: parse_vec3(&matches, "sky-color").unwrap(),
+        env_map: matches.value_of_os("env-map").map(PathBuf::from),
+        texture_wrap: match matches.value_of("texture-wrap") {
+            Some("repeat") => Wrap::Repeat,
+            Some("clamp") => Wrap::Clamp,
+            Some("mirror") => Wrap::Mirror,
+            other => panic!("BUG: unhandled texture-wrap {:?}", other),
+        },
+        delta_lights: matches.values_of("light")
+            .map_or(Vec::new(), |lights| lights.map(|s| s.parse().unwrap()).collect()),
+        lights_file: matches.value_of_os("lights-file").map(PathBuf::from),
+    };
+    if let Err(msg) = check_camera(&cfg) {
+        clap::Error::with_description(&msg, clap::ErrorKind::InvalidValue).exit();
+    }
+    cfg
+}
+
+/// Check the camera settings that can't be validated one argument at a time.
+fn check_camera(cfg: &Config) -> Result<(), String> {
+    if cfg.projection == Projection::Perspective &&
+       cfg.camera_fov.map_or(false, |fov| fov >= 180.0) {
+        return Err("--fov must be less than 180 degrees with the perspective projection"
+                       .to_string());
     }
+    // The default eye depends on the model, so without it the view direction is only known if
+    // the target is the default too.
+    let eye = match (cfg.camera_eye, cfg.camera_target) {
+        (Some(eye), _) => eye,
+        (None, None) => vec3(0.0, 0.0, 0.0),
+        (None, Some(_)) => return Ok(()),
+    };
+    let target = cfg.camera_target.unwrap_or_else(|| camera::default_target(eye));
+    camera::view_basis(eye, target, cfg.camera_up).map(|_| ())
 }
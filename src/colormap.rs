@@ -0,0 +1,216 @@
+use cast::{u8, usize, f64};
+use output::Pixel;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMap {
+    Grey,
+    Red,
+    Viridis,
+    Inferno,
+    Turbo,
+    /// Diverging map from blue through white to red, for signed differences.
+    BlueRed,
+}
+
+// Polynomial fits of the color maps, coefficients are in order of increasing degree.
+// Viridis and inferno are fits by Matt Zucker, turbo is Google's own approximation.
+const VIRIDIS: [[f64; 7]; 3] = [[0.2777273272234177,
+                                 0.1050930431085774,
+                                 -0.3308618287255563,
+                                 -4.634230498983486,
+                                 6.228269936347081,
+                                 4.776384997670288,
+                                 -5.435455855934631],
+                                [0.005407344544966578,
+                                 1.404613529898575,
+                                 0.214847559468213,
+                                 -5.799100973351585,
+                                 14.17993336680509,
+                                 -13.74514537774601,
+                                 4.645852612178535],
+                                [0.3340998053353061,
+                                 1.384590162594685,
+                                 0.09509516302823659,
+                                 -19.33244095627987,
+                                 56.69055260068105,
+                                 -65.35303263337234,
+                                 26.3124352495832]];
+const INFERNO: [[f64; 7]; 3] = [[0.0002189403691192265,
+                                 0.1065134194856116,
+                                 11.60249308247187,
+                                 -41.70399613139459,
+                                 77.162935699427,
+                                 -71.31942824499214,
+                                 25.13112622477341],
+                                [0.001651004631001012,
+                                 0.5639564367884091,
+                                 -3.972853965665698,
+                                 17.43639888205313,
+                                 -33.40235894210092,
+                                 32.62606426397723,
+                                 -12.24266895238567],
+                                [-0.01948089843709184,
+                                 3.932712388889277,
+                                 -15.9423941062914,
+                                 44.35414519872813,
+                                 -81.80730925738993,
+                                 73.20951985803202,
+                                 -23.07032500287172]];
+const TURBO: [[f64; 7]; 3] = [[0.13572138,
+                               4.61539260,
+                               -42.66032258,
+                               132.13108234,
+                               -152.94239396,
+                               59.28637943,
+                               0.0],
+                              [0.09140261,
+                               2.19418839,
+                               4.84296658,
+                               -14.18503333,
+                               4.27729857,
+                               2.82956604,
+                               0.0],
+                              [0.10667330,
+                               12.64194608,
+                               -60.58204836,
+                               110.36276771,
+                               -89.90310912,
+                               27.34824973,
+                               0.0]];
+
+impl ColorMap {
+    /// Map t \in [0, 1] to a color.
+    pub fn color(&self, t: f64) -> Pixel {
+        let to_u8 = |x: f64| u8((x.max(0.0).min(1.0) * 255.0).round()).unwrap();
+        let poly = |coeffs: &[[f64; 7]; 3]| {
+            let eval = |c: &[f64; 7]| c.iter().rev().fold(0.0, |acc, &c| acc * t + c);
+            Pixel {
+                r: to_u8(eval(&coeffs[0])),
+                g: to_u8(eval(&coeffs[1])),
+                b: to_u8(eval(&coeffs[2])),
+            }
+        };
+        match *self {
+            ColorMap::Grey => {
+                let s = to_u8(t);
+                Pixel { r: s, g: s, b: s }
+            }
+            ColorMap::Red => Pixel { r: to_u8(t), g: 0, b: 0 },
+            ColorMap::Viridis => poly(&VIRIDIS),
+            ColorMap::Inferno => poly(&INFERNO),
+            ColorMap::Turbo => poly(&TURBO),
+            ColorMap::BlueRed => {
+                let s = to_u8(1.0 - (2.0 * t - 1.0).abs());
+                if t < 0.5 {
+                    Pixel { r: s, g: s, b: 255 }
+                } else {
+                    Pixel { r: 255, g: s, b: s }
+                }
+            }
+        }
+    }
+}
+
+/// How values are mapped to the color map.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scale {
+    Linear,
+    /// Logarithmic, for long-tailed distributions.
+    Log,
+    /// Linear, but the given percentage of values at either end is clipped.
+    Percentile(f64),
+}
+
+/// Visualization options shared by all scalar render kinds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Style {
+    pub colormap: ColorMap,
+    pub scale: Scale,
+    /// Draw a color bar with the minimum and maximum value into the image.
+    pub legend: bool,
+    /// Map this range of values to the color map instead of the range of the image.
+    /// This makes the colors of different images comparable.
+    pub fixed_range: Option<(f64, f64)>,
+}
+
+/// The ratio between the largest and smallest (non-zero) value that the log scale spans
+/// if the range starts at or below zero.
+const LOG_SCALE_SPAN: f64 = 1000.0;
+
+/// The range of values that is mapped to the full color map.
+/// The log scale doesn't depend on the unit of the values.
+pub struct ValueRange {
+    min: f64,
+    max: f64,
+    log: bool,
+}
+
+impl ValueRange {
+    /// Determine the range of `values` according to the scale.
+    /// Without any values, e.g. if no ray hit the model, the range is 0 to 1.
+    pub fn new(mut values: Vec<f64>, scale: Scale) -> Self {
+        if values.is_empty() {
+            return ValueRange::fixed(0.0, 1.0, scale);
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let clip = match scale {
+            Scale::Percentile(p) => p / 100.0,
+            Scale::Linear | Scale::Log => 0.0,
+        };
+        let last = values.len() - 1;
+        let lo = usize((f64(last) * clip).round()).unwrap();
+        let hi = usize((f64(last) * (1.0 - clip)).round()).unwrap();
+        ValueRange {
+            min: values[lo],
+            max: values[hi.max(lo)],
+            log: scale == Scale::Log,
+        }
+    }
+
+    /// Use an explicit range instead of determining it from the values.
+    pub fn fixed(min: f64, max: f64, scale: Scale) -> Self {
+        ValueRange {
+            min,
+            max,
+            log: scale == Scale::Log,
+        }
+    }
+
+    /// Determine the range of `values`, unless the style demands a fixed range.
+    pub fn for_style(values: Vec<f64>, style: &Style) -> Self {
+        match style.fixed_range {
+            Some((min, max)) => ValueRange::fixed(min, max, style.scale),
+            None => ValueRange::new(values, style.scale),
+        }
+    }
+
+    /// Map `x` to [0, 1], clamping values outside the range.
+    pub fn normalize(&self, x: f64) -> f64 {
+        if self.max <= self.min {
+            return 0.0;
+        }
+        let x = x.max(self.min).min(self.max);
+        if !self.log {
+            (x - self.min) / (self.max - self.min)
+        } else if self.min > 0.0 {
+            (x / self.min).ln() / (self.max / self.min).ln()
+        } else {
+            // A logarithm of the values themselves would be -inf at zero. Instead, the range is
+            // compressed as if it started at a thousandth of its length.
+            let t = (x - self.min) / (self.max - self.min);
+            (LOG_SCALE_SPAN * t).ln_1p() / LOG_SCALE_SPAN.ln_1p()
+        }
+    }
+
+    /// The inverse of `normalize`.
+    pub fn value_at(&self, t: f64) -> f64 {
+        if !self.log {
+            self.min + t * (self.max - self.min)
+        } else if self.min > 0.0 {
+            self.min * (self.max / self.min).powf(t)
+        } else {
+            let t = (t * LOG_SCALE_SPAN.ln_1p()).exp_m1() / LOG_SCALE_SPAN;
+            self.min + t * (self.max - self.min)
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use cast::{f32, usize};
+use cgmath::{Vector3, vec3};
+use image::ImageError;
+use image::hdr::HDRDecoder;
+use output::FloatImage;
+use sampling::{Rng, sample_discrete};
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+/// The light arriving from infinitely far away, where rays don't hit the scene.
+pub enum Environment {
+    /// The same radiance from every direction.
+    Constant(Vector3<f32>),
+    Map(EnvMap),
+}
+
+/// A direction sampled towards the environment.
+pub struct EnvSample {
+    pub wi: Vector3<f32>,
+    pub radiance: Vector3<f32>,
+    /// The probability density of the direction, per unit solid angle.
+    pub pdf: f32,
+}
+
+impl Environment {
+    /// The radiance arriving from direction `d` (which need not be normalized).
+    pub fn radiance(&self, d: Vector3<f32>) -> Vector3<f32> {
+        match *self {
+            Environment::Constant(radiance) => radiance,
+            Environment::Map(ref map) => map.radiance(d),
+        }
+    }
+
+    /// Sample a direction in proportion to the radiance arriving from it.
+    /// Returns `None` if the environment can't be importance sampled.
+    pub fn sample(&self, rng: &mut Rng) -> Option<EnvSample> {
+        match *self {
+            Environment::Constant(_) => None,
+            Environment::Map(ref map) => map.sample(rng),
+        }
+    }
+
+    /// The probability density with which `sample` returns the direction `d`.
+    pub fn pdf(&self, d: Vector3<f32>) -> f32 {
+        match *self {
+            Environment::Constant(_) => 0.0,
+            Environment::Map(ref map) => map.pdf(d),
+        }
+    }
+}
+
+/// An environment map in equirectangular (latitude-longitude) layout.
+/// The top row is straight up (+Y) and the center of the image faces -Z.
+pub struct EnvMap {
+    width: u32,
+    height: u32,
+    /// Radiance, row by row, top to bottom.
+    texels: Vec<Vector3<f32>>,
+    /// The probability of picking each texel for importance sampling.
+    texel_pdf: Vec<f32>,
+    /// Cumulative probabilities of the rows.
+    row_cdf: Vec<f32>,
+    /// Cumulative probabilities of the texels in every row, given that row.
+    column_cdfs: Vec<f32>,
+}
+
+impl EnvMap {
+    /// Load a Radiance HDR (.hdr) or PFM image.
+    pub fn load(path: &Path) -> io::Result<EnvMap> {
+        let ext = path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase());
+        let (width, height, texels) = match ext.as_ref().map(|ext| &ext[..]) {
+            Some("hdr") => read_hdr(path)?,
+            Some("pfm") => read_pfm(path)?,
+            _ => {
+                let msg = format!("unsupported environment map format: {}", path.display());
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        if width == 0 || height == 0 {
+            let msg = format!("empty environment map: {}", path.display());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok(EnvMap::new(width, height, texels))
+    }
+
+    fn new(width: u32, height: u32, texels: Vec<Vector3<f32>>) -> Self {
+        let (w, h) = (usize(width), usize(height));
+        // Texels are importance sampled by luminance, weighted by the solid angle they cover,
+        // which shrinks towards the poles.
+        let mut weights: Vec<f32> = texels.iter()
+            .enumerate()
+            .map(|(i, t)| {
+                     let sin_theta = (PI * (f32(i / w) + 0.5) / f32(height)).sin();
+                     luminance(*t).max(0.0) * sin_theta
+                 })
+            .collect();
+        let total: f32 = weights.iter().sum();
+        if total > 0.0 {
+            for x in &mut weights {
+                *x /= total;
+            }
+        }
+        let mut row_cdf = Vec::with_capacity(h);
+        let mut column_cdfs = Vec::with_capacity(w * h);
+        let mut acc = 0.0;
+        for row in weights.chunks(w) {
+            let row_total: f32 = row.iter().sum();
+            acc += row_total;
+            row_cdf.push(acc);
+            let mut row_acc = 0.0;
+            for (j, x) in row.iter().enumerate() {
+                row_acc += *x;
+                // Rows without any weight are never picked, so any valid CDF will do.
+                column_cdfs.push(if row_total > 0.0 {
+                                     row_acc / row_total
+                                 } else {
+                                     f32(j + 1) / f32(width)
+                                 });
+            }
+        }
+        EnvMap {
+            width,
+            height,
+            texels,
+            texel_pdf: weights,
+            row_cdf,
+            column_cdfs,
+        }
+    }
+
+    fn texel_index(&self, d: Vector3<f32>) -> usize {
+        let (u, v) = direction_to_uv(d);
+        let x = usize(u * f32(self.width)).unwrap_or(0).min(usize(self.width) - 1);
+        let y = usize(v * f32(self.height)).unwrap_or(0).min(usize(self.height) - 1);
+        y * usize(self.width) + x
+    }
+
+    fn radiance(&self, d: Vector3<f32>) -> Vector3<f32> {
+        self.texels[self.texel_index(d)]
+    }
+
+    fn sample(&self, rng: &mut Rng) -> Option<EnvSample> {
+        if self.row_cdf.last().map_or(true, |&total| total <= 0.0) {
+            return None;
+        }
+        let w = usize(self.width);
+        let y = sample_discrete(&self.row_cdf, rng.next_f32());
+        let x = sample_discrete(&self.column_cdfs[y * w..(y + 1) * w], rng.next_f32());
+        let (du, dv) = rng.next_2d();
+        let u = (f32(x) + du) / f32(self.width);
+        let v = (f32(y) + dv) / f32(self.height);
+        let wi = uv_to_direction(u, v);
+        let pdf = self.texel_pdf_to_solid_angle(self.texel_pdf[y * w + x], v);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(EnvSample {
+                 wi,
+                 radiance: self.texels[y * w + x],
+                 pdf,
+             })
+    }
+
+    fn pdf(&self, d: Vector3<f32>) -> f32 {
+        let (_, v) = direction_to_uv(d);
+        self.texel_pdf_to_solid_angle(self.texel_pdf[self.texel_index(d)], v)
+    }
+
+    /// Convert the probability of a texel into a density per unit solid angle,
+    /// at latitude `v` within the texel.
+    fn texel_pdf_to_solid_angle(&self, p: f32, v: f32) -> f32 {
+        let sin_theta = (PI * v).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        p * f32(self.width) * f32(self.height) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+fn luminance(c: Vector3<f32>) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+fn direction_to_uv(d: Vector3<f32>) -> (f32, f32) {
+    let len = (d.x * d.x + d.y * d.y + d.z * d.z).sqrt();
+    let theta = (d.y / len).max(-1.0).min(1.0).acos();
+    let phi = d.x.atan2(-d.z);
+    ((phi + PI) / (2.0 * PI), theta / PI)
+}
+
+fn uv_to_direction(u: f32, v: f32) -> Vector3<f32> {
+    let (phi, theta) = (2.0 * PI * u - PI, PI * v);
+    vec3(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())
+}
+
+fn read_hdr(path: &Path) -> io::Result<(u32, u32, Vec<Vector3<f32>>)> {
+    let decoder = HDRDecoder::new(BufReader::new(File::open(path)?)).map_err(image_error)?;
+    let meta = decoder.metadata();
+    let pixels = decoder.read_image_hdr().map_err(image_error)?;
+    let texels = pixels.iter().map(|px| vec3(px.data[0], px.data[1], px.data[2])).collect();
+    Ok((meta.width, meta.height, texels))
+}
+
+fn image_error(e: ImageError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn read_pfm(path: &Path) -> io::Result<(u32, u32, Vec<Vector3<f32>>)> {
+    let img = FloatImage::load_pfm(path)?;
+    let (width, height) = (img.width(), img.height());
+    let channels = img.into_channels();
+    let c = |i: usize, j: usize| channels[i].values.float_at(j);
+    let texels = (0..usize(width) * usize(height))
+        .map(|j| if channels.len() == 3 {
+                 vec3(c(0, j), c(1, j), c(2, j))
+             } else {
+                 vec3(c(0, j), c(0, j), c(0, j))
+             })
+        .collect();
+    Ok((width, height, texels))
+}
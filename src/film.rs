@@ -1,9 +1,16 @@
-use bmp;
-use cast::{usize, u32, u8};
+use cast::{usize, u32, u8, f32};
+use cgmath::{Vector3, vec3};
+use colormap::{Scale, Style, ValueRange};
+use filter::Filter;
 use itertools::{Itertools, MinMaxResult};
+use legend;
 use ordered_float::NotNaN;
+use output::{self, Channel, FloatImage, Image, Pixel, Values};
 use rayon::prelude::*;
-use std::{f32, iter, slice};
+use std::{cmp, f32, iter, slice, u32};
+
+/// How many columns `Frame::add_samples` samples in parallel before merging them.
+const STRIP_BATCH: u32 = 64;
 
 pub struct Frame<T> {
     width: u32,
@@ -20,6 +27,34 @@ impl<T: Sync + Send + Copy> Frame<T> {
         }
     }
 
+    pub fn map<U, F>(&self, f: F) -> Frame<U>
+        where F: Fn(T) -> U,
+              U: Sync + Send + Copy
+    {
+        Frame {
+            width: self.width,
+            height: self.height,
+            buffer: self.pixel_values().map(f).collect(),
+        }
+    }
+
+    /// Combine two frames of the same size pixel by pixel.
+    pub fn zip_map<U, V, F>(&self, other: &Frame<U>, f: F) -> Frame<V>
+        where F: Fn(T, U) -> V,
+              U: Sync + Send + Copy,
+              V: Sync + Send + Copy
+    {
+        assert_eq!((self.width, self.height), (other.width, other.height));
+        Frame {
+            width: self.width,
+            height: self.height,
+            buffer: self.pixel_values()
+                .zip(other.pixel_values())
+                .map(|(a, b)| f(a, b))
+                .collect(),
+        }
+    }
+
     pub fn for_each_pixel<F>(&self, mut f: F)
         where F: FnMut(u32, u32, T)
     {
@@ -32,20 +67,64 @@ impl<T: Sync + Send + Copy> Frame<T> {
         }
     }
 
-    pub fn set_pixels<F>(&mut self, f: F)
-        where F: Send + Sync + Fn(u32, u32) -> T
+    /// Compute every pixel from samples generated by `f`, which returns the samples taken for
+    /// pixel (x, y) with their position in the pixel (in the unit square).
+    /// Each sample contributes to all pixels within the radius of the reconstruction filter.
+    pub fn add_samples<F>(&mut self, filter: &Filter, f: F)
+        where F: Send + Sync + Fn(u32, u32) -> Vec<((f32, f32), T)>,
+              T: Sample
     {
-        // TODO why height and not width?
-        let height = self.height;
-        self.buffer[..]
-            .par_iter_mut()
-            .enumerate()
-            // TODO iterate differently to avoid the divmod
-            .for_each(move |(i, px)| {
-                let x = u32(i).unwrap() / height;
-                let y = u32(i).unwrap() % height;
-                *px = f(x, y);
-            });
+        let (width, height) = (self.width, self.height);
+        // How many neighboring pixels in each direction a sample can contribute to,
+        // i.e., how far away the farthest pixel center within the filter radius can be.
+        let reach = u32((filter.radius() - 0.5).ceil().max(0.0)).unwrap();
+        let strip_width = 2 * reach + 1;
+        // Every column is sampled independently and splats its samples into a strip of
+        // neighboring columns. The columns are sampled in batches, whose strips are merged in
+        // order, so that the result doesn't depend on scheduling. Besides the sums, this only
+        // takes memory for the strips of one batch.
+        let mut sums = vec![T::empty_sum(); self.buffer.len()];
+        for batch in 0..(width + STRIP_BATCH - 1) / STRIP_BATCH {
+            let columns = batch * STRIP_BATCH..cmp::min((batch + 1) * STRIP_BATCH, width);
+            let strips: Vec<Vec<T::Sum>> = columns.clone()
+                .into_par_iter()
+                .map(|x| {
+                    let mut strip = vec![T::empty_sum(); usize(strip_width) * usize(height)];
+                    for y in 0..height {
+                        for ((sx, sy), value) in f(x, y) {
+                            let (px, py) = (f32(x) + sx, f32(y) + sy);
+                            for nx in x.saturating_sub(reach)..cmp::min(x + reach + 1, width) {
+                                for ny in y.saturating_sub(reach)..
+                                          cmp::min(y + reach + 1, height) {
+                                    let weight = filter.eval(px - (f32(nx) + 0.5),
+                                                             py - (f32(ny) + 0.5));
+                                    if weight != 0.0 {
+                                        let i = (nx + reach - x) * height + ny;
+                                        value.add_to(&mut strip[usize(i)], weight);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    strip
+                })
+                .collect();
+            // The sums are laid out like `buffer`, i.e., column by column.
+            for (x, strip) in columns.zip(strips) {
+                for nx in x.saturating_sub(reach)..cmp::min(x + reach + 1, width) {
+                    let strip_column = usize(nx + reach - x) * usize(height);
+                    let column = usize(nx) * usize(height);
+                    for y in 0..usize(height) {
+                        T::merge(&mut sums[column + y], strip[strip_column + y]);
+                    }
+                }
+            }
+        }
+        self.buffer = sums.into_iter().map(T::average).collect();
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> T {
+        self.buffer[usize(x) * usize(self.height) + usize(y)]
     }
 
     fn pixel_values(&self) -> iter::Cloned<slice::Iter<T>>
@@ -54,13 +133,231 @@ impl<T: Sync + Send + Copy> Frame<T> {
         self.buffer.iter().cloned()
     }
 
-    fn to_bmp<F>(&self, f: F) -> bmp::Image
-        where F: Fn(T) -> bmp::Pixel
+    fn to_image<F>(&self, f: F) -> Image
+        where F: Fn(T) -> Pixel
     {
-        let mut img = bmp::Image::new(self.width, self.height);
+        let mut img = Image::new(self.width, self.height);
         self.for_each_pixel(|x, y, px| { img.set_pixel(x, y, f(px)); });
         img
     }
+
+    /// Like `to_image`, but where `f` returns `None` because no ray hit anything, the backdrop
+    /// shows through. Without a backdrop, those pixels get the color `default`.
+    fn to_image_over<F>(&self, backdrop: Option<&Backdrop>, default: Pixel, f: F) -> Image
+        where F: Fn(T) -> Option<Pixel>
+    {
+        let mut img = Image::new(self.width, self.height);
+        self.for_each_pixel(|x, y, px| {
+            let color = f(px).unwrap_or_else(|| backdrop.map_or(default, |b| b.color(x, y)));
+            img.set_pixel(x, y, color);
+        });
+        img
+    }
+
+    /// The pixels mapped by `f`, row by row, as channels store them.
+    fn to_rows<U, F>(&self, f: F) -> Vec<U>
+        where U: Clone + Default,
+              F: Fn(T) -> U
+    {
+        let mut values = vec![U::default(); self.buffer.len()];
+        let width = self.width;
+        self.for_each_pixel(|x, y, px| { values[usize(y * width + x)] = f(px); });
+        values
+    }
+
+    fn to_channel<F>(&self, name: &str, f: F) -> Channel
+        where F: Fn(T) -> f32
+    {
+        Channel {
+            name: name.to_string(),
+            values: Values::Float(self.to_rows(f)),
+        }
+    }
+
+    fn to_float_image<F>(&self, name: &str, f: F) -> FloatImage
+        where F: Fn(T) -> f32
+    {
+        FloatImage::new(self.width, self.height, vec![self.to_channel(name, f)])
+    }
+}
+
+/// Pixel values that can be averaged over multiple samples.
+pub trait Sample: Copy {
+    /// Running weighted sum of samples.
+    type Sum: Copy + Send;
+
+    fn empty_sum() -> Self::Sum;
+    fn add_to(self, sum: &mut Self::Sum, weight: f32);
+    fn merge(sum: &mut Self::Sum, other: Self::Sum);
+    /// Compute the weighted average of all samples that were added to the sum.
+    fn average(sum: Self::Sum) -> Self;
+}
+
+/// Floats are used for depth, where infinity means that the ray didn't hit anything.
+/// Infinite samples are ignored, so the result is only infinite if no sample was finite.
+impl Sample for f32 {
+    type Sum = (f32, f32);
+
+    fn empty_sum() -> Self::Sum {
+        (0.0, 0.0)
+    }
+
+    fn add_to(self, sum: &mut Self::Sum, weight: f32) {
+        if self.is_finite() {
+            sum.0 += weight * self;
+            sum.1 += weight;
+        }
+    }
+
+    fn merge(sum: &mut Self::Sum, other: Self::Sum) {
+        sum.0 += other.0;
+        sum.1 += other.1;
+    }
+
+    fn average((sum, total_weight): Self::Sum) -> Self {
+        if total_weight > 0.0 {
+            sum / total_weight
+        } else {
+            f32::INFINITY
+        }
+    }
+}
+
+impl Sample for u32 {
+    type Sum = (f32, f32);
+
+    fn empty_sum() -> Self::Sum {
+        (0.0, 0.0)
+    }
+
+    fn add_to(self, sum: &mut Self::Sum, weight: f32) {
+        sum.0 += weight * f32(self);
+        sum.1 += weight;
+    }
+
+    fn merge(sum: &mut Self::Sum, other: Self::Sum) {
+        sum.0 += other.0;
+        sum.1 += other.1;
+    }
+
+    fn average((sum, total_weight): Self::Sum) -> Self {
+        if total_weight > 0.0 {
+            u32((sum / total_weight).round().max(0.0)).unwrap()
+        } else {
+            0
+        }
+    }
+}
+
+/// Vectors such as normals and positions are only defined where a ray hit something.
+/// The average is taken over the samples that are defined.
+impl Sample for Option<Vector3<f32>> {
+    type Sum = (Vector3<f32>, f32);
+
+    fn empty_sum() -> Self::Sum {
+        (vec3(0.0, 0.0, 0.0), 0.0)
+    }
+
+    fn add_to(self, sum: &mut Self::Sum, weight: f32) {
+        if let Some(v) = self {
+            sum.0 += v * weight;
+            sum.1 += weight;
+        }
+    }
+
+    fn merge(sum: &mut Self::Sum, other: Self::Sum) {
+        sum.0 += other.0;
+        sum.1 += other.1;
+    }
+
+    fn average((sum, total_weight): Self::Sum) -> Self {
+        if total_weight > 0.0 {
+            Some(sum / total_weight)
+        } else {
+            None
+        }
+    }
+}
+
+/// Scalars that are only defined where a ray hit something, such as shading values.
+/// The average is taken over the samples that are defined.
+impl Sample for Option<f32> {
+    type Sum = (f32, f32);
+
+    fn empty_sum() -> Self::Sum {
+        (0.0, 0.0)
+    }
+
+    fn add_to(self, sum: &mut Self::Sum, weight: f32) {
+        if let Some(x) = self {
+            sum.0 += weight * x;
+            sum.1 += weight;
+        }
+    }
+
+    fn merge(sum: &mut Self::Sum, other: Self::Sum) {
+        sum.0 += other.0;
+        sum.1 += other.1;
+    }
+
+    fn average((sum, total_weight): Self::Sum) -> Self {
+        if total_weight > 0.0 {
+            Some(sum / total_weight)
+        } else {
+            None
+        }
+    }
+}
+
+/// Radiance, as computed by the path tracer. Every sample carries a value.
+impl Sample for Vector3<f32> {
+    type Sum = (Vector3<f32>, f32);
+
+    fn empty_sum() -> Self::Sum {
+        (vec3(0.0, 0.0, 0.0), 0.0)
+    }
+
+    fn add_to(self, sum: &mut Self::Sum, weight: f32) {
+        sum.0 += self * weight;
+        sum.1 += weight;
+    }
+
+    fn merge(sum: &mut Self::Sum, other: Self::Sum) {
+        sum.0 += other.0;
+        sum.1 += other.1;
+    }
+
+    fn average((sum, total_weight): Self::Sum) -> Self {
+        if total_weight > 0.0 {
+            sum / total_weight
+        } else {
+            vec3(0.0, 0.0, 0.0)
+        }
+    }
+}
+
+/// Identifiers (such as triangle IDs) can't be averaged.
+/// Instead, the pixel gets the ID of the sample with the largest weight.
+impl Sample for Option<u32> {
+    type Sum = (Option<u32>, f32);
+
+    fn empty_sum() -> Self::Sum {
+        (None, f32::NEG_INFINITY)
+    }
+
+    fn add_to(self, sum: &mut Self::Sum, weight: f32) {
+        Self::merge(sum, (self, weight));
+    }
+
+    fn merge(sum: &mut Self::Sum, other: Self::Sum) {
+        if other.1 > sum.1 {
+            *sum = other;
+        }
+    }
+
+    fn average((id, _): Self::Sum) -> Self {
+        id
+    }
 }
 
 /// Compute the linear interpolation coefficient for producing x from x0 and x1, i.e.,
@@ -73,43 +370,267 @@ fn inv_lerp<T: Copy + Into<f64> + PartialOrd>(x: T, x0: T, x1: T) -> f64 {
     t
 }
 
-pub trait ToBmp {
-    fn to_bmp(&self) -> bmp::Image;
+/// Conversion of a rendered frame into images that can be saved.
+pub trait ToImage {
+    /// A visualization for displaying.
+    /// Pixels where no ray hit the scene show the backdrop, if there is one.
+    fn to_image(&self, backdrop: Option<&Backdrop>) -> Image;
+    /// The raw pixel values, for analysis.
+    fn to_float_image(&self) -> FloatImage;
 }
 
-pub struct Depthmap(pub Frame<f32>);
-pub struct Heatmap(pub Frame<u32>);
+pub struct Depthmap(pub Frame<f32>, pub Style);
+pub struct Heatmap(pub Frame<u32>, pub Style);
+/// Signed difference of two heatmaps.
+pub struct HeatDiffmap(pub Frame<f32>, pub Style);
+/// Scalars that are only defined where a ray hit something.
+pub struct ScalarMap(pub Frame<Option<f32>>, pub Style);
+
+/// The environment as seen by the camera, shown behind the scene.
+pub struct Backdrop(pub Frame<Vector3<f32>>);
 
-impl ToBmp for Depthmap {
-    fn to_bmp(&self) -> bmp::Image {
-        let frame = &self.0;
-        let (min_depth, max_depth) = match frame.pixel_values()
-                  .filter(|&x| x != f32::INFINITY)
-                  .minmax_by_key(|&x| NotNaN::new(x).unwrap()) {
-            MinMaxResult::MinMax(min, max) => (min, max),
-            _ => panic!("frame empty or not a single pixel"),
+impl Backdrop {
+    fn color(&self, x: u32, y: u32) -> Pixel {
+        tone_map(self.0.get(x, y))
+    }
+}
+
+impl ToImage for Depthmap {
+    fn to_image(&self, backdrop: Option<&Backdrop>) -> Image {
+        let Depthmap(ref frame, style) = *self;
+        let depths = frame.pixel_values().filter(|&x| x != f32::INFINITY).map(f64::from);
+        let range = ValueRange::for_style(depths.collect(), &style);
+        // Near surfaces are at the high end of the color map.
+        let mut img = frame.to_image_over(backdrop, output::BLUE, |depth| {
+            if depth == f32::INFINITY {
+                None
+            } else {
+                let t = range.normalize(f64::from(depth));
+                Some(style.colormap.color(1.0 - t))
+            }
+        });
+        if style.legend {
+            legend::draw(&mut img, style.colormap, |t| range.value_at(1.0 - t));
+        }
+        img
+    }
+
+    fn to_float_image(&self) -> FloatImage {
+        self.0.to_float_image("Z", |depth| depth)
+    }
+}
+
+impl ToImage for Heatmap {
+    fn to_image(&self, _: Option<&Backdrop>) -> Image {
+        let Heatmap(ref frame, style) = *self;
+        let heats = frame.pixel_values().map(f64::from);
+        let range = ValueRange::for_style(heats.collect(), &style);
+        let mut img = frame.to_image(|heat| {
+                                         let t = range.normalize(f64::from(heat));
+                                         style.colormap.color(t)
+                                     });
+        if style.legend {
+            legend::draw(&mut img, style.colormap, |t| range.value_at(t));
+        }
+        img
+    }
+
+    fn to_float_image(&self) -> FloatImage {
+        self.0.to_float_image("Y", |heat| f32(heat))
+    }
+}
+
+impl ToImage for HeatDiffmap {
+    fn to_image(&self, _: Option<&Backdrop>) -> Image {
+        let HeatDiffmap(ref frame, style) = *self;
+        // The range is symmetric, so that zero is always in the middle of the color map.
+        let max_diff = match style.fixed_range {
+            Some((_, max)) => max,
+            None => frame.pixel_values().fold(0.0, |acc, d| f64::from(d.abs()).max(acc)),
         };
-        frame.to_bmp(|depth| if depth == f32::INFINITY {
-                         bmp::consts::BLUE
-                     } else {
-                         let intensity = inv_lerp(depth, min_depth, max_depth);
-                         let s = u8(((1.0 - intensity) * 255.0).round()).unwrap();
-                         bmp::Pixel { r: s, g: s, b: s }
-                     })
-    }
-}
-
-impl ToBmp for Heatmap {
-    fn to_bmp(&self) -> bmp::Image {
-        let frame = &self.0;
-        let (min_heat, max_heat) = match frame.pixel_values().minmax() {
-            MinMaxResult::MinMax(min, max) => (min, max),
-            _ => panic!("frame empty or a single pixel"),
+        // Identical BVHs (the default) give no differences at all. Any non-empty range puts
+        // zero in the middle, for the image and the legend alike.
+        let max_diff = if max_diff > 0.0 { max_diff } else { 1.0 };
+        let range = ValueRange::fixed(-max_diff, max_diff, Scale::Linear);
+        let mut img = frame.to_image(|diff| {
+                                         let t = range.normalize(f64::from(diff));
+                                         style.colormap.color(t)
+                                     });
+        if style.legend {
+            legend::draw(&mut img, style.colormap, |t| range.value_at(t));
+        }
+        img
+    }
+
+    fn to_float_image(&self) -> FloatImage {
+        self.0.to_float_image("Y", |diff| diff)
+    }
+}
+
+impl ToImage for ScalarMap {
+    fn to_image(&self, backdrop: Option<&Backdrop>) -> Image {
+        let ScalarMap(ref frame, style) = *self;
+        let values = frame.pixel_values().filter_map(|x| x).map(f64::from);
+        let range = ValueRange::for_style(values.collect(), &style);
+        let mut img = frame.to_image_over(backdrop, output::BLUE, |x| {
+            x.map(|x| style.colormap.color(range.normalize(f64::from(x))))
+        });
+        if style.legend {
+            legend::draw(&mut img, style.colormap, |t| range.value_at(t));
+        }
+        img
+    }
+
+    fn to_float_image(&self) -> FloatImage {
+        self.0.to_float_image("Y", |x| x.unwrap_or(f32::NAN))
+    }
+}
+
+/// Three-dimensional values such as normals or positions, shown as RGB.
+pub struct VectorMap {
+    pub frame: Frame<Option<Vector3<f32>>>,
+    /// The range of component values that is mapped to [0, 255].
+    /// If it is `None`, the range of all components in the frame is used.
+    pub range: Option<(f32, f32)>,
+    pub channel_names: [&'static str; 3],
+}
+
+/// IDs shown as random colors, so that neighboring IDs can be told apart.
+pub struct IdMap(pub Frame<Option<u32>>);
+
+/// The raw value of pixels without an ID.
+pub const NO_ID: u32 = u32::MAX;
+
+impl ToImage for VectorMap {
+    fn to_image(&self, backdrop: Option<&Backdrop>) -> Image {
+        let (min, max) = match self.range {
+            Some(range) => range,
+            None => {
+                let components = self.frame
+                    .pixel_values()
+                    .filter_map(|v| v)
+                    .flat_map(|v| vec![v.x, v.y, v.z]);
+                match components.minmax_by_key(|&x| NotNaN::new(x).unwrap()) {
+                    MinMaxResult::MinMax(min, max) => (min, max),
+                    MinMaxResult::OneElement(x) => (x, x),
+                    // Nothing was hit, so there's nothing to normalize.
+                    MinMaxResult::NoElements => (0.0, 1.0),
+                }
+            }
+        };
+        let to_u8 = |x: f32| {
+            // If all components are equal, show them in the middle of the range.
+            let intensity = if min < max {
+                inv_lerp(x.max(min).min(max), min, max)
+            } else {
+                0.5
+            };
+            u8((intensity * 255.0).round()).unwrap()
+        };
+        self.frame.to_image_over(backdrop, Pixel { r: 0, g: 0, b: 0 }, |v| {
+            v.map(|v| {
+                      Pixel {
+                          r: to_u8(v.x),
+                          g: to_u8(v.y),
+                          b: to_u8(v.z),
+                      }
+                  })
+        })
+    }
+
+    fn to_float_image(&self) -> FloatImage {
+        let channels = (0..3)
+            .map(|i| {
+                     self.frame.to_channel(self.channel_names[i],
+                                           |v| v.map(|v| v[i]).unwrap_or(f32::NAN))
+                 })
+            .collect();
+        FloatImage::new(self.frame.width, self.frame.height, channels)
+    }
+}
+
+impl ToImage for IdMap {
+    fn to_image(&self, backdrop: Option<&Backdrop>) -> Image {
+        self.0.to_image_over(backdrop, Pixel { r: 0, g: 0, b: 0 }, |id| {
+            id.map(|id| {
+                       let h = hash_id(id);
+                       Pixel {
+                           r: h as u8,
+                           g: (h >> 8) as u8,
+                           b: (h >> 16) as u8,
+                       }
+                   })
+        })
+    }
+
+    /// The IDs are stored exactly, pixels without an ID get `NO_ID`.
+    fn to_float_image(&self) -> FloatImage {
+        let ids = Channel {
+            name: "id".to_string(),
+            values: Values::Uint(self.0.to_rows(|id| id.unwrap_or(NO_ID))),
+        };
+        FloatImage::new(self.0.width, self.0.height, vec![ids])
+    }
+}
+
+/// Linear RGB radiance, tone mapped for display.
+pub struct RadianceMap(pub Frame<Vector3<f32>>);
+
+impl ToImage for RadianceMap {
+    /// The path tracer already renders the environment, so the backdrop isn't needed.
+    fn to_image(&self, _: Option<&Backdrop>) -> Image {
+        self.0.to_image(tone_map)
+    }
+
+    fn to_float_image(&self) -> FloatImage {
+        let channels = ["R", "G", "B"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| self.0.to_channel(name, |v| v[i]))
+            .collect();
+        FloatImage::new(self.0.width, self.0.height, channels)
+    }
+}
+
+/// Map linear RGB radiance to a displayable color.
+/// Reinhard's operator compresses the unbounded radiance into [0, 1),
+/// then the result is encoded as sRGB.
+fn tone_map(v: Vector3<f32>) -> Pixel {
+    let to_u8 = |x: f32| {
+        // x / (1 + x) is NaN for +inf, so saturate explicitly. NaN samples become black.
+        let x = if x.is_nan() {
+            0.0
+        } else if x == f32::INFINITY {
+            1.0
+        } else {
+            let x = x.max(0.0);
+            x / (1.0 + x)
         };
-        frame.to_bmp(|heat| {
-                         let intensity = inv_lerp(heat, min_heat, max_heat);
-                         let s = u8((intensity * 255.0).round()).unwrap();
-                         bmp::Pixel { r: s, g: 0, b: 0 }
-                     })
+        (srgb_encode(x) * 255.0).round().max(0.0).min(255.0) as u8
+    };
+    Pixel {
+        r: to_u8(v.x),
+        g: to_u8(v.y),
+        b: to_u8(v.z),
     }
 }
+
+/// The sRGB transfer function, for linear values in [0, 1].
+fn srgb_encode(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Scramble the bits of an ID (this is the finalizer of MurmurHash3).
+fn hash_id(id: u32) -> u32 {
+    let mut h = id;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
@@ -0,0 +1,79 @@
+use std::f32::consts::PI;
+
+/// Pixel reconstruction filters. All of them are separable, i.e., the 2D filter is the product
+/// of the 1D filter applied to the x and y offsets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    /// Mitchell-Netravali with B = C = 1/3
+    Mitchell,
+    /// Lanczos-windowed sinc with two lobes
+    Lanczos,
+}
+
+const GAUSSIAN_ALPHA: f32 = 2.0;
+const MITCHELL_B: f32 = 1.0 / 3.0;
+const MITCHELL_C: f32 = 1.0 / 3.0;
+
+impl Filter {
+    /// Offsets (in pixels) beyond this distance have zero weight.
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell | Filter::Lanczos => 2.0,
+        }
+    }
+
+    /// Weight of a sample at offset (dx, dy) from the pixel center.
+    pub fn eval(&self, dx: f32, dy: f32) -> f32 {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+
+    fn eval_1d(&self, x: f32) -> f32 {
+        let r = self.radius();
+        match *self {
+            // Half-open so that samples on pixel boundaries aren't counted twice.
+            Filter::Box => if -r <= x && x < r { 1.0 } else { 0.0 },
+            Filter::Tent => (1.0 - x.abs()).max(0.0),
+            Filter::Gaussian => {
+                // Shifted down so that it falls off to zero at the radius.
+                let g = |x: f32| (-GAUSSIAN_ALPHA * x * x).exp();
+                (g(x) - g(r)).max(0.0)
+            }
+            Filter::Mitchell => mitchell_1d(x.abs()),
+            Filter::Lanczos => {
+                if x.abs() < r {
+                    sinc(x) * sinc(x / r)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+fn mitchell_1d(x: f32) -> f32 {
+    let (b, c) = (MITCHELL_B, MITCHELL_C);
+    let (x2, x3) = (x * x, x * x * x);
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)) /
+        6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x3 + (6.0 * b + 30.0 * c) * x2 + (-12.0 * b - 48.0 * c) * x +
+         (8.0 * b + 24.0 * c)) / 6.0
+    } else {
+        0.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
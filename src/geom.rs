@@ -1,7 +1,7 @@
 use beebox::Aabb;
 use beevage;
 use cast::u32;
-use cgmath::Vector3;
+use cgmath::{InnerSpace, Vector3, vec3};
 use std::{f32, u32};
 use std::cell::Cell;
 use watertri;
@@ -17,6 +17,22 @@ impl Tri {
     pub fn bbox(&self) -> Aabb {
         Aabb::new([self.a, self.b, self.c].iter().cloned())
     }
+
+    /// The unit normal of the triangle's plane, oriented according to the winding order.
+    pub fn normal(&self) -> Vector3<f32> {
+        (self.b - self.a).cross(self.c - self.a).normalize()
+    }
+
+    pub fn area(&self) -> f32 {
+        (self.b - self.a).cross(self.c - self.a).magnitude() / 2.0
+    }
+
+    /// Map a point in the unit square to a point on the triangle, uniformly distributed by area.
+    pub fn sample_point(&self, (u, v): (f32, f32)) -> Vector3<f32> {
+        let su = u.sqrt();
+        let (b0, b1) = (1.0 - su, v * su);
+        self.a * b0 + self.b * b1 + self.c * (1.0 - b0 - b1)
+    }
 }
 
 impl beevage::Primitive for Tri {
@@ -25,10 +41,22 @@ impl beevage::Primitive for Tri {
     }
 }
 
+/// Construct two unit vectors that form an orthonormal basis together with the unit vector `n`.
+/// This is the method by Duff et al., "Building an Orthonormal Basis, Revisited".
+pub fn orthonormal_basis(n: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let sign = if n.z >= 0.0 { 1.0 } else { -1.0 };
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (vec3(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+     vec3(b, sign + n.y * n.y * a, -n.y))
+}
+
 #[derive(Debug)]
 pub struct Ray {
     pub o: Vector3<f32>,
     pub d: Vector3<f32>,
+    /// Intersections closer than this are ignored.
+    pub t_min: f32,
     pub t_max: Cell<f32>,
     pub traversal_steps: Cell<u32>,
 }
@@ -38,12 +66,34 @@ impl Ray {
         Ray {
             o: origin,
             d: direction,
+            t_min: 0.0,
             t_max: Cell::new(f32::INFINITY),
             traversal_steps: Cell::new(0),
         }
     }
 }
 
+/// Move the point `p` on a surface with geometric normal `n` off the surface, far enough that
+/// rays starting there don't intersect the surface again due to rounding errors.
+/// The offset is applied on the side `n` points to, so flip it for rays leaving the other side.
+///
+/// This is the method from Wächter and Binder, "A Fast and Robust Method for Avoiding
+/// Self-Intersection", Ray Tracing Gems (2019): the offset is a fixed number of ULPs, which
+/// scales with the magnitude of the coordinates, except close to the origin.
+pub fn offset_ray_origin(p: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
+    const ORIGIN: f32 = 1.0 / 32.0;
+    const FLOAT_SCALE: f32 = 1.0 / 65536.0;
+    const INT_SCALE: f32 = 256.0;
+    let offset = |p: f32, n: f32| if p.abs() < ORIGIN {
+        p + FLOAT_SCALE * n
+    } else {
+        let ulps = (INT_SCALE * n) as i32;
+        let ulps = if p < 0.0 { -ulps } else { ulps };
+        <f32>::from_bits((p.to_bits() as i32).wrapping_add(ulps) as u32)
+    };
+    vec3(offset(p.x, n.x), offset(p.y, n.y), offset(p.z, n.z))
+}
+
 const INVALID_ID: u32 = u32::MAX;
 
 pub struct Hit {
@@ -91,13 +141,15 @@ impl Hit {
 pub trait TriSliceExt {
     fn bbox(&self) -> Aabb;
     fn intersect(&self, offset: u32, ray: &Ray, ray_data: &watertri::RayData, hit: &mut Hit);
+    /// Whether the ray hits any of the triangles between `t_min` and `t_max`.
+    fn intersects_any(&self, ray: &Ray, ray_data: &watertri::RayData) -> bool;
 }
 
 impl TriSliceExt for [Tri] {
     fn intersect(&self, offset: u32, ray: &Ray, ray_data: &watertri::RayData, hit: &mut Hit) {
         for (i, tri) in self.iter().enumerate() {
             if let Some(intersection) = ray_data.intersect(tri.a, tri.b, tri.c) {
-                if intersection.t < ray.t_max.get() {
+                if ray.t_min < intersection.t && intersection.t < ray.t_max.get() {
                     ray.t_max.set(intersection.t);
                     hit.replace(offset + u32(i).unwrap(), intersection);
                 }
@@ -105,6 +157,15 @@ impl TriSliceExt for [Tri] {
         }
     }
 
+    fn intersects_any(&self, ray: &Ray, ray_data: &watertri::RayData) -> bool {
+        self.iter().any(|tri| match ray_data.intersect(tri.a, tri.b, tri.c) {
+                            Some(intersection) => {
+                                ray.t_min < intersection.t && intersection.t < ray.t_max.get()
+                            }
+                            None => false,
+                        })
+    }
+
     fn bbox(&self) -> Aabb {
         let mut res = Aabb::empty();
         for tri in self {
@@ -0,0 +1,225 @@
+use super::Config;
+use bsdf::{Bsdf, LocalFrame};
+use cast::{f32, usize};
+use cgmath::{ElementWise, InnerSpace, Vector3, vec3};
+use geom::{Hit, Ray, offset_ray_origin};
+use sampling::{Rng, cosine_hemisphere, power_heuristic};
+use scene::Scene;
+
+/// The fraction of the hemisphere above the hit point that is not occluded within
+/// `max_distance`, estimated with `samples` cosine-weighted rays.
+pub fn ambient_occlusion(scene: &Scene,
+                         hit: &Hit,
+                         r: &Ray,
+                         samples: u32,
+                         max_distance: f32,
+                         rng: &mut Rng)
+                         -> f32 {
+    let mut n = scene.geometric_normal(hit);
+    // Sample the hemisphere on the side of the surface the ray came from.
+    if n.dot(r.d) > 0.0 {
+        n = -n;
+    }
+    let origin = offset_ray_origin(scene.hit_point(hit), n);
+    let mut unoccluded = 0u32;
+    for _ in 0..samples {
+        let ao_ray = Ray::new(origin, cosine_hemisphere(n, rng.next_2d()));
+        ao_ray.t_max.set(max_distance);
+        if !scene.occluded(&ao_ray) {
+            unoccluded += 1;
+        }
+    }
+    f32(unoccluded) / f32(samples)
+}
+
+/// Paths are only terminated by Russian roulette after this many bounces.
+const MIN_BOUNCES: u32 = 3;
+/// Shadow rays stop this fraction of the distance short of the light, so they don't hit it.
+const SHADOW_EPSILON: f32 = 1e-3;
+
+/// Estimate the radiance arriving along the primary ray `r`, which hit the scene at `hit`.
+/// Paths are scattered by the surfaces' BSDFs until they escape to the environment, are
+/// terminated by Russian roulette, or exceed the maximum number of bounces.
+/// At every vertex, a point on an emissive triangle and a direction towards the environment are
+/// sampled for direct lighting. This is combined with hitting emitters by BSDF sampling using
+/// multiple importance sampling. Delta lights can't be hit, so they are always sampled.
+pub fn path_trace(scene: &Scene, mut hit: Hit, mut r: Ray, cfg: &Config, rng: &mut Rng)
+                  -> Vector3<f32> {
+    let mut radiance = vec3(0.0, 0.0, 0.0);
+    let mut throughput = vec3(1.0, 1.0, 1.0);
+    let mut bounces = 0;
+    // Primary rays and specular bounces can't be handled by light sampling.
+    let mut specular = true;
+    // The solid angle density with which the BSDF sampled the current ray.
+    let mut bsdf_pdf = 0.0;
+    loop {
+        if !hit.is_valid() {
+            let env = scene.environment();
+            let weight = if specular {
+                1.0
+            } else {
+                power_heuristic(bsdf_pdf, env.pdf(r.d))
+            };
+            return radiance + throughput.mul_element_wise(env.radiance(r.d)) * weight;
+        }
+        let material = scene.material(&hit);
+        if material.is_emissive() {
+            let weight = if specular {
+                1.0
+            } else {
+                power_heuristic(bsdf_pdf, scene.light_pdf(&hit, &r))
+            };
+            radiance += throughput.mul_element_wise(material.emission) * weight;
+        }
+        if bounces == cfg.max_bounces {
+            return radiance;
+        }
+        // The shading normal is oriented like the geometric normal, i.e., by the winding order,
+        // so that transparent materials can tell the inside from the outside.
+        let ng = scene.geometric_normal(&hit);
+        let mut ns = scene.shading_normal(&hit);
+        if ns.dot(ng) < 0.0 {
+            ns = -ns;
+        }
+        let frame = LocalFrame::new(ns);
+        let wo = frame.to_local(-r.d);
+        let bsdf = scene.bsdf(&hit);
+        let p = scene.hit_point(&hit);
+        let direct = direct_light(scene, &*bsdf, &frame, wo, p, ng, rng) +
+                     environment_light(scene, &*bsdf, &frame, wo, p, ng, rng) +
+                     delta_lights(scene, &*bsdf, &frame, wo, p, ng);
+        radiance += throughput.mul_element_wise(direct);
+        let sample = match bsdf.sample(wo, rng) {
+            Some(sample) => sample,
+            None => return radiance,
+        };
+        let wi = frame.to_world(sample.wi);
+        // The shading normal can put directions on the wrong side of the actual surface.
+        if (wi.dot(ng) > 0.0) != (sample.wi.z > 0.0) {
+            return radiance;
+        }
+        throughput = throughput.mul_element_wise(sample.weight);
+        specular = sample.specular;
+        bsdf_pdf = sample.pdf;
+        if bounces >= MIN_BOUNCES {
+            let survival = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+            if rng.next_f32() >= survival {
+                return radiance;
+            }
+            throughput /= survival;
+        }
+        let offset_normal = if wi.dot(ng) > 0.0 { ng } else { -ng };
+        r = Ray::new(offset_ray_origin(p, offset_normal), wi);
+        hit = scene.intersect(&r);
+        bounces += 1;
+    }
+}
+
+/// Estimate the radiance scattered into `wo` from light arriving directly from a point sampled
+/// on the emissive triangles, using a shadow ray to test visibility.
+/// The result is weighted for multiple importance sampling with BSDF sampling.
+fn direct_light(scene: &Scene,
+                bsdf: &Bsdf,
+                frame: &LocalFrame,
+                wo: Vector3<f32>,
+                p: Vector3<f32>,
+                ng: Vector3<f32>,
+                rng: &mut Rng)
+                -> Vector3<f32> {
+    let zero = vec3(0.0, 0.0, 0.0);
+    let light = match scene.sample_light(rng) {
+        Some(light) => light,
+        None => return zero,
+    };
+    let to_light = light.point - p;
+    let dist2 = to_light.magnitude2();
+    if dist2 == 0.0 {
+        return zero;
+    }
+    let dist = dist2.sqrt();
+    let wi = to_light / dist;
+    let wi_local = frame.to_local(wi);
+    if (wi.dot(ng) > 0.0) != (wi_local.z > 0.0) {
+        return zero;
+    }
+    let f = bsdf.eval(wo, wi_local);
+    let cos_light = scene.tris[usize(light.tri_id)].normal().dot(wi).abs();
+    if f == zero || cos_light == 0.0 {
+        return zero;
+    }
+    let offset_normal = if wi.dot(ng) > 0.0 { ng } else { -ng };
+    let shadow_ray = Ray::new(offset_ray_origin(p, offset_normal), wi);
+    shadow_ray.t_max.set(dist * (1.0 - SHADOW_EPSILON));
+    if scene.occluded(&shadow_ray) {
+        return zero;
+    }
+    // Convert the area density of the light sample to solid angle.
+    let light_pdf = light.pdf * dist2 / cos_light;
+    let weight = power_heuristic(light_pdf, bsdf.pdf(wo, wi_local));
+    let emission = scene.tri_material(light.tri_id).emission;
+    f.mul_element_wise(emission) * (wi_local.z.abs() * weight / light_pdf)
+}
+
+/// Like `direct_light`, but for light from the environment, in a direction sampled from it.
+fn environment_light(scene: &Scene,
+                     bsdf: &Bsdf,
+                     frame: &LocalFrame,
+                     wo: Vector3<f32>,
+                     p: Vector3<f32>,
+                     ng: Vector3<f32>,
+                     rng: &mut Rng)
+                     -> Vector3<f32> {
+    let zero = vec3(0.0, 0.0, 0.0);
+    let sample = match scene.environment().sample(rng) {
+        Some(sample) => sample,
+        None => return zero,
+    };
+    let wi_local = frame.to_local(sample.wi);
+    if (sample.wi.dot(ng) > 0.0) != (wi_local.z > 0.0) {
+        return zero;
+    }
+    let f = bsdf.eval(wo, wi_local);
+    if f == zero {
+        return zero;
+    }
+    let offset_normal = if sample.wi.dot(ng) > 0.0 { ng } else { -ng };
+    if scene.occluded(&Ray::new(offset_ray_origin(p, offset_normal), sample.wi)) {
+        return zero;
+    }
+    let weight = power_heuristic(sample.pdf, bsdf.pdf(wo, wi_local));
+    f.mul_element_wise(sample.radiance) * (wi_local.z.abs() * weight / sample.pdf)
+}
+
+/// The radiance scattered into `wo` from all of the scene's delta lights, each tested for
+/// visibility with a shadow ray.
+fn delta_lights(scene: &Scene,
+                bsdf: &Bsdf,
+                frame: &LocalFrame,
+                wo: Vector3<f32>,
+                p: Vector3<f32>,
+                ng: Vector3<f32>)
+                -> Vector3<f32> {
+    let mut radiance = vec3(0.0, 0.0, 0.0);
+    for light in scene.delta_lights() {
+        let sample = match light.illuminate(p) {
+            Some(sample) => sample,
+            None => continue,
+        };
+        let wi_local = frame.to_local(sample.wi);
+        if (sample.wi.dot(ng) > 0.0) != (wi_local.z > 0.0) {
+            continue;
+        }
+        let f = bsdf.eval(wo, wi_local);
+        if f == vec3(0.0, 0.0, 0.0) {
+            continue;
+        }
+        let offset_normal = if sample.wi.dot(ng) > 0.0 { ng } else { -ng };
+        let shadow_ray = Ray::new(offset_ray_origin(p, offset_normal), sample.wi);
+        shadow_ray.t_max.set(sample.distance * (1.0 - SHADOW_EPSILON));
+        if scene.occluded(&shadow_ray) {
+            continue;
+        }
+        radiance += f.mul_element_wise(sample.irradiance) * wi_local.z.abs();
+    }
+    radiance
+}
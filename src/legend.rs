@@ -0,0 +1,101 @@
+use cast::{f64, u32};
+use colormap::ColorMap;
+use output::{Image, Pixel};
+
+const BAR_WIDTH: u32 = 16;
+const MARGIN: u32 = 8;
+/// Glyphs are scaled up by this factor.
+const FONT_SCALE: u32 = 2;
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+
+const WHITE: Pixel = Pixel { r: 255, g: 255, b: 255 };
+const BLACK: Pixel = Pixel { r: 0, g: 0, b: 0 };
+
+/// Draw a vertical color bar along the right edge of the image, labeled with the values at
+/// either end. `t_to_value` computes the value corresponding to a color map position.
+pub fn draw<F>(img: &mut Image, colormap: ColorMap, t_to_value: F)
+    where F: Fn(f64) -> f64
+{
+    let (width, height) = (img.width(), img.height());
+    let label_height = FONT_SCALE * GLYPH_HEIGHT;
+    if width < BAR_WIDTH + 2 * MARGIN || height < 2 * (MARGIN + label_height) {
+        // Too small to fit anything useful.
+        return;
+    }
+    let x0 = width - MARGIN - BAR_WIDTH;
+    let (y0, y1) = (MARGIN, height - MARGIN);
+    for y in y0..y1 {
+        // The top of the bar is t = 1.
+        let t = 1.0 - f64(y - y0) / f64(y1 - y0 - 1);
+        let color = colormap.color(t);
+        for x in x0..x0 + BAR_WIDTH {
+            img.set_pixel(x, y, color);
+        }
+    }
+    let max_label = format_value(t_to_value(1.0));
+    let min_label = format_value(t_to_value(0.0));
+    draw_text(img, &max_label, x0, y0);
+    draw_text(img, &min_label, x0, y1 - label_height);
+}
+
+fn format_value(x: f64) -> String {
+    if x != 0.0 && (x.abs() >= 1e5 || x.abs() < 1e-2) {
+        format!("{:.2e}", x)
+    } else {
+        format!("{:.2}", x)
+    }
+}
+
+/// Draw `text` right-aligned to `right`, with its top at `top`, on a black background.
+fn draw_text(img: &mut Image, text: &str, right: u32, top: u32) {
+    let advance = FONT_SCALE * (GLYPH_WIDTH + 1);
+    let text_width = advance * u32(text.len()).unwrap() + FONT_SCALE;
+    let left = right.saturating_sub(text_width + FONT_SCALE);
+    for y in top.saturating_sub(FONT_SCALE)..top + FONT_SCALE * (GLYPH_HEIGHT + 1) {
+        for x in left..right {
+            img.set_pixel(x, y, BLACK);
+        }
+    }
+    for (i, c) in text.chars().enumerate() {
+        let glyph = glyph(c);
+        let gx = left + FONT_SCALE + advance * u32(i).unwrap();
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                let (px, py) = (gx + FONT_SCALE * col, top + FONT_SCALE * u32(row).unwrap());
+                for dy in 0..FONT_SCALE {
+                    for dx in 0..FONT_SCALE {
+                        if px + dx < img.width() {
+                            img.set_pixel(px + dx, py + dy, WHITE);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A 3x5 pixel font with just enough characters for numbers.
+/// Every row is a bit mask, the most significant of the three bits is the leftmost pixel.
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        'e' => [0b000, 0b111, 0b111, 0b100, 0b011],
+        _ => [0b111, 0b111, 0b111, 0b111, 0b111],
+    }
+}
@@ -0,0 +1,261 @@
+use cast::{u32, usize};
+use cgmath::{InnerSpace, Vector3, vec3};
+use geom::Tri;
+use sampling::{Rng, sample_discrete};
+use std::f32;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+/// A point sampled on an area light.
+pub struct LightSample {
+    pub tri_id: u32,
+    pub point: Vector3<f32>,
+    /// The probability density of the point, per unit area.
+    pub pdf: f32,
+}
+
+/// The emissive triangles of a scene, for sampling points on them.
+pub struct AreaLights {
+    tri_ids: Vec<u32>,
+    /// Cumulative areas of the triangles, normalized to 1.
+    cdf: Vec<f32>,
+    total_area: f32,
+}
+
+impl AreaLights {
+    /// Collect the triangles for which `is_emissive` returns true.
+    pub fn new<F>(tris: &[Tri], is_emissive: F) -> Self
+        where F: Fn(usize) -> bool
+    {
+        let tri_ids: Vec<u32> = (0..tris.len())
+            .filter(|&i| is_emissive(i) && tris[i].area() > 0.0)
+            .map(|i| u32(i).unwrap())
+            .collect();
+        let mut total_area = 0.0;
+        let mut cdf: Vec<f32> = tri_ids.iter()
+            .map(|&i| {
+                     total_area += tris[usize(i)].area();
+                     total_area
+                 })
+            .collect();
+        for c in &mut cdf {
+            *c /= total_area;
+        }
+        AreaLights {
+            tri_ids,
+            cdf,
+            total_area,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tri_ids.is_empty()
+    }
+
+    /// Sample a point uniformly distributed over the total area of all lights.
+    pub fn sample(&self, tris: &[Tri], rng: &mut Rng) -> Option<LightSample> {
+        if self.is_empty() {
+            return None;
+        }
+        let tri_id = self.tri_ids[sample_discrete(&self.cdf, rng.next_f32())];
+        Some(LightSample {
+                 tri_id,
+                 point: tris[usize(tri_id)].sample_point(rng.next_2d()),
+                 pdf: self.pdf(),
+             })
+    }
+
+    /// The probability density per unit area of sampling any point on a light.
+    pub fn pdf(&self) -> f32 {
+        1.0 / self.total_area
+    }
+}
+
+/// A light without area, which is only reached by shadow rays towards it.
+/// Lights are written as in `--light` or a lights file, e.g. `point 0,2,0 10,10,10`.
+#[derive(Clone, Debug)]
+pub enum DeltaLight {
+    /// Emits the same radiant intensity in all directions.
+    Point {
+        position: Vector3<f32>,
+        intensity: Vector3<f32>,
+    },
+    /// A point light restricted to a cone around `direction`. The intensity falls off smoothly
+    /// from full strength at the inner angle to zero at the outer angle.
+    Spot {
+        position: Vector3<f32>,
+        direction: Vector3<f32>,
+        intensity: Vector3<f32>,
+        cos_inner: f32,
+        cos_outer: f32,
+    },
+    /// Parallel light travelling in `direction`, as from the sun, with the given irradiance on
+    /// surfaces facing it.
+    Directional {
+        direction: Vector3<f32>,
+        irradiance: Vector3<f32>,
+    },
+}
+
+/// The light arriving at a point from a delta light.
+pub struct DeltaLightSample {
+    /// The unit direction towards the light.
+    pub wi: Vector3<f32>,
+    /// The distance to the light, infinite for directional lights.
+    pub distance: f32,
+    /// The irradiance on a surface perpendicular to `wi`, if nothing is in between.
+    pub irradiance: Vector3<f32>,
+}
+
+impl DeltaLight {
+    /// The light arriving at `p`, without testing for occluders.
+    /// Returns `None` if the light doesn't shine on `p`.
+    pub fn illuminate(&self, p: Vector3<f32>) -> Option<DeltaLightSample> {
+        match *self {
+            DeltaLight::Point { position, intensity } => point_light(position, intensity, p),
+            DeltaLight::Spot { position, direction, intensity, cos_inner, cos_outer } => {
+                let mut sample = match point_light(position, intensity, p) {
+                    Some(sample) => sample,
+                    None => return None,
+                };
+                let falloff = smoothstep(cos_outer, cos_inner, (-sample.wi).dot(direction));
+                if falloff == 0.0 {
+                    return None;
+                }
+                sample.irradiance *= falloff;
+                Some(sample)
+            }
+            DeltaLight::Directional { direction, irradiance } => {
+                Some(DeltaLightSample {
+                         wi: -direction,
+                         distance: f32::INFINITY,
+                         irradiance,
+                     })
+            }
+        }
+    }
+}
+
+fn point_light(position: Vector3<f32>, intensity: Vector3<f32>, p: Vector3<f32>)
+               -> Option<DeltaLightSample> {
+    let to_light = position - p;
+    let dist2 = to_light.magnitude2();
+    if dist2 == 0.0 {
+        return None;
+    }
+    let distance = dist2.sqrt();
+    Some(DeltaLightSample {
+             wi: to_light / distance,
+             distance,
+             irradiance: intensity / dist2,
+         })
+}
+
+/// Hermite interpolation from 0 at `edge0` to 1 at `edge1`, a step if the edges coincide.
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge0 >= edge1 {
+        return if x >= edge1 { 1.0 } else { 0.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+impl FromStr for DeltaLight {
+    type Err = String;
+
+    /// Parse a light from whitespace-separated fields, where vectors are written as `X,Y,Z`:
+    ///
+    /// - `point POSITION INTENSITY`
+    /// - `spot POSITION DIRECTION INTENSITY ANGLE [INNER_ANGLE]`, with the half-angles of the
+    ///   cone in degrees. Without an inner angle, the cone has a hard edge.
+    /// - `directional DIRECTION IRRADIANCE`
+    fn from_str(s: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let light = match (fields.get(0).cloned(), fields.len()) {
+            (Some("point"), 3) => {
+                DeltaLight::Point {
+                    position: parse_vec3(fields[1])?,
+                    intensity: parse_vec3(fields[2])?,
+                }
+            }
+            (Some("spot"), 5) | (Some("spot"), 6) => {
+                let angle = parse_angle(fields[4])?;
+                let inner_angle = match fields.get(5) {
+                    Some(s) => parse_angle(s)?.min(angle),
+                    None => angle,
+                };
+                DeltaLight::Spot {
+                    position: parse_vec3(fields[1])?,
+                    direction: parse_direction(fields[2])?,
+                    intensity: parse_vec3(fields[3])?,
+                    cos_inner: inner_angle.to_radians().cos(),
+                    cos_outer: angle.to_radians().cos(),
+                }
+            }
+            (Some("directional"), 3) => {
+                DeltaLight::Directional {
+                    direction: parse_direction(fields[1])?,
+                    irradiance: parse_vec3(fields[2])?,
+                }
+            }
+            _ => {
+                return Err(format!("Light must be 'point POSITION INTENSITY', \
+                                    'spot POSITION DIRECTION INTENSITY ANGLE [INNER_ANGLE]' \
+                                    or 'directional DIRECTION IRRADIANCE', not '{}'",
+                                   s))
+            }
+        };
+        Ok(light)
+    }
+}
+
+fn parse_vec3(s: &str) -> Result<Vector3<f32>, String> {
+    let xs: Vec<f32> = match s.split(',').map(|x| x.parse()).collect() {
+        Ok(xs) => xs,
+        Err(_) => return Err(format!("'{}' is not a vector of numbers", s)),
+    };
+    if xs.len() == 3 {
+        Ok(vec3(xs[0], xs[1], xs[2]))
+    } else {
+        Err(format!("'{}' must have three components 'X,Y,Z'", s))
+    }
+}
+
+fn parse_direction(s: &str) -> Result<Vector3<f32>, String> {
+    let d = parse_vec3(s)?;
+    if d.magnitude2() > 0.0 {
+        Ok(d.normalize())
+    } else {
+        Err(format!("direction '{}' must not be zero", s))
+    }
+}
+
+fn parse_angle(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(angle) if angle >= 0.0 && angle <= 180.0 => Ok(angle),
+        _ => Err(format!("'{}' must be an angle between 0 and 180 degrees", s)),
+    }
+}
+
+/// Read a lights file, with one light per line in the syntax of `DeltaLight::from_str`.
+/// Empty lines and lines starting with `#` are ignored.
+pub fn read_lights_file(path: &Path) -> io::Result<Vec<DeltaLight>> {
+    let mut lights = Vec::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.parse() {
+            Ok(light) => lights.push(light),
+            Err(msg) => {
+                let msg = format!("{}:{}: {}", path.display(), i + 1, msg);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        }
+    }
+    Ok(lights)
+}
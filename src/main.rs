@@ -7,35 +7,76 @@ extern crate cgmath;
 extern crate clap;
 extern crate cast;
 extern crate elapsed;
+extern crate image;
 #[macro_use]
 extern crate lazy_static;
 extern crate itertools;
 extern crate obj;
 extern crate ordered_float;
+extern crate png;
 extern crate rayon;
 extern crate regex;
 extern crate watertri;
 
+use aov::{Aov, AovSample};
+use camera::{Camera, Projection};
 use cast::{usize, u32, f32, f64};
-use cgmath::{InnerSpace, vec3};
-use film::{Frame, Depthmap, Heatmap};
+use cgmath::{InnerSpace, Vector3, vec3};
+use colormap::{ColorMap, Scale, Style};
+use film::{Backdrop, Frame, Depthmap, Heatmap, HeatDiffmap, IdMap, RadianceMap, Sample, ScalarMap,
+           ToImage, VectorMap};
+use filter::Filter;
 use geom::{Hit, Ray};
+use light::DeltaLight;
+use output::{Format, FloatImage};
+use sampling::{Rng, stratified_2d};
 use scene::Scene;
 use std::f32;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
+use texture::Wrap;
 
+mod aov;
+mod bsdf;
 mod bvh;
+mod camera;
 mod cli;
+mod colormap;
+mod envmap;
 mod film;
+mod filter;
 mod geom;
+mod integrator;
+mod legend;
+mod light;
+mod material;
+mod output;
+mod sampling;
 mod scene;
+mod texture;
 
+#[derive(Clone)]
 enum RenderKind {
     Depthmap,
     Heatmap,
+    /// Heatmaps for two BVHs built with different parameters, and their difference.
+    HeatDiff,
+    Aovs,
+    Normals,
+    /// The cosine between surface normal and view direction.
+    FacingRatio,
+    /// Simple diffuse shading with a light at the camera. Back faces are tinted red.
+    Headlight,
+    /// Every triangle in a random color.
+    TriangleIds,
+    /// Every BVH leaf in a random color.
+    LeafIds,
+    AmbientOcclusion,
+    PathTrace,
 }
 
+#[derive(Clone)]
 pub struct Config {
     input_file: PathBuf,
     output_file: PathBuf,
@@ -45,42 +86,285 @@ pub struct Config {
     sah_traversal_cost: f32,
     num_threads: Option<u32>,
     render_kind: RenderKind,
+    camera_eye: Option<Vector3<f32>>,
+    camera_target: Option<Vector3<f32>>,
+    camera_up: Vector3<f32>,
+    /// Vertical field of view in degrees.
+    camera_fov: Option<f32>,
+    projection: Projection,
+    /// Height of the orthographic view volume in world units.
+    ortho_height: Option<f32>,
+    lens_radius: f32,
+    focus_distance: Option<f32>,
+    samples_per_pixel: u32,
+    seed: u64,
+    filter: Filter,
+    aovs: Vec<Aov>,
+    /// Color map for scalar render kinds, `None` means each kind's own default.
+    colormap: Option<ColorMap>,
+    scale: Scale,
+    legend: bool,
+    /// Number of traversal steps mapped to the top of the color map, instead of the maximum.
+    heat_max: Option<u32>,
+    /// BVH parameters to compare against with `RenderKind::HeatDiff`.
+    diff_sah_buckets: u32,
+    diff_sah_traversal_cost: f32,
+    ao_samples: u32,
+    /// Occluders farther away than this are ignored, `None` means relative to the model size.
+    ao_distance: Option<f32>,
+    /// Maximum number of times a path can bounce off surfaces.
+    max_bounces: u32,
+    /// Radiance arriving from every direction that doesn't hit the scene,
+    /// unless there is an environment map.
+    sky_color: Vector3<f32>,
+    /// Equirectangular HDR image of the light arriving from every direction.
+    env_map: Option<PathBuf>,
+    /// How texture coordinates outside the texture are treated.
+    texture_wrap: Wrap,
+    /// Lights given on the command line, in addition to those in `lights_file`.
+    delta_lights: Vec<DeltaLight>,
+    /// File with more delta lights, one per line.
+    lights_file: Option<PathBuf>,
 }
 
-fn primary_ray(x: u32, y: u32, cfg: &Config) -> Ray {
-    let norm_x = (f32(x) + 0.5) / f32(cfg.image_width);
-    let norm_y = (f32(y) + 0.5) / f32(cfg.image_height);
-    let aspect_ratio = f32(cfg.image_width) / f32(cfg.image_height);
-    let cam_x = aspect_ratio * (norm_x - 0.5);
-    let cam_y = aspect_ratio * (0.5 - norm_y);
-    let d = vec3(cam_x, cam_y, -1.0).normalize();
-    Ray::new(vec3(0.0, 0.0, 0.0), d)
+impl Config {
+    fn style(&self, default_colormap: ColorMap) -> Style {
+        Style {
+            colormap: self.colormap.unwrap_or(default_colormap),
+            scale: self.scale,
+            legend: self.legend,
+            fixed_range: None,
+        }
+    }
+
+    /// The configuration for the second BVH of `RenderKind::HeatDiff`.
+    fn diff_bvh_config(&self) -> Config {
+        Config {
+            sah_buckets: self.diff_sah_buckets,
+            sah_traversal_cost: self.diff_sah_traversal_cost,
+            ..self.clone()
+        }
+    }
+
+    fn heat_style(&self) -> Style {
+        Style {
+            fixed_range: self.heat_max.map(|max| (0.0, f64(max))),
+            ..self.style(ColorMap::Red)
+        }
+    }
 }
 
+/// The images created by a render, each with a name that distinguishes it from the others.
+type Outputs = Vec<(&'static str, Box<ToImage>)>;
+
 fn render<T, F>(scene: &Scene, cfg: &Config, background: T, shader: F) -> film::Frame<T>
-    where F: Sync + Fn(Hit, Ray) -> T,
-          T: Copy + Send + Sync
+    where F: Sync + Fn(Hit, Ray, &mut Rng) -> T,
+          T: Sample + Send + Sync
 {
+    trace_primary_rays(scene, cfg, background, |r, rng| {
+        let hit = scene.intersect(&r);
+        shader(hit, r, rng)
+    })
+}
+
+/// Compute every pixel from the values `f` computes for the camera rays through it.
+/// Where the camera has no ray, e.g. outside the fisheye image circle, `background` is used.
+fn trace_primary_rays<T, F>(scene: &Scene, cfg: &Config, background: T, f: F) -> film::Frame<T>
+    where F: Sync + Fn(Ray, &mut Rng) -> T,
+          T: Sample + Send + Sync
+{
+    let camera = Camera::new(cfg, &scene.bbox());
     let mut frame = Frame::new(cfg.image_width, cfg.image_height, background);
-    frame.set_pixels(|x, y| {
-                         let r = primary_ray(x, y, cfg);
-                         let hit = scene.intersect(&r);
-                         shader(hit, r)
-                     });
+    frame.add_samples(&cfg.filter, |x, y| {
+        let mut rng = Rng::for_pixel(cfg.seed, x, y);
+        stratified_2d(cfg.samples_per_pixel, &mut rng)
+            .into_iter()
+            .map(|pixel_sample| {
+                let value = match camera.primary_ray(x, y, pixel_sample, rng.next_2d()) {
+                    Some(r) => f(r, &mut rng),
+                    None => background,
+                };
+                (pixel_sample, value)
+            })
+            .collect()
+    });
     frame
 }
 
-fn render_depthmap(scene: &Scene, cfg: &Config) -> Box<film::ToBmp> {
+/// The environment map as seen through the camera, for compositing the other render kinds over.
+fn render_backdrop(scene: &Scene, cfg: &Config) -> Backdrop {
+    let frame = trace_primary_rays(scene,
+                                   cfg,
+                                   vec3(0.0, 0.0, 0.0),
+                                   |r, _| scene.environment().radiance(r.d));
+    Backdrop(frame)
+}
+
+fn render_depthmap(scene: &Scene, cfg: &Config) -> Outputs {
     let frame = render(scene,
                        cfg,
                        f32::INFINITY,
-                       |hit, _| if hit.is_valid() { hit.t } else { f32::INFINITY });
-    Box::new(Depthmap(frame))
+                       |hit, _, _| if hit.is_valid() { hit.t } else { f32::INFINITY });
+    let image: Box<ToImage> = Box::new(Depthmap(frame, cfg.style(ColorMap::Grey)));
+    vec![("depth", image)]
 }
 
-fn render_heatmap(scene: &Scene, cfg: &Config) -> Box<film::ToBmp> {
-    let frame = render(scene, cfg, 0, |_, r| r.traversal_steps.get());
-    Box::new(Heatmap(frame))
+fn render_heatmap(scene: &Scene, cfg: &Config) -> Outputs {
+    let frame = render(scene, cfg, 0, |_, r, _| r.traversal_steps.get());
+    let image: Box<ToImage> = Box::new(Heatmap(frame, cfg.heat_style()));
+    vec![("heat", image)]
+}
+
+/// Compare the BVH of `scene` with that of `other_scene`, which has the same triangles.
+fn render_heat_diff(scene: &Scene, other_scene: &Scene, cfg: &Config) -> Outputs {
+    let frame_a = render(scene, cfg, 0, |_, r, _| r.traversal_steps.get());
+    let frame_b = render(other_scene, cfg, 0, |_, r, _| r.traversal_steps.get());
+    let diff = frame_b.zip_map(&frame_a, |b, a| f32(b) - f32(a));
+    let heat_a: Box<ToImage> = Box::new(Heatmap(frame_a, cfg.heat_style()));
+    let heat_b: Box<ToImage> = Box::new(Heatmap(frame_b, cfg.heat_style()));
+    let diff_style = Style {
+        fixed_range: cfg.heat_max.map(|max| (-f64(max), f64(max))),
+        ..cfg.style(ColorMap::BlueRed)
+    };
+    let diff: Box<ToImage> = Box::new(HeatDiffmap(diff, diff_style));
+    vec![("heat-a", heat_a), ("heat-b", heat_b), ("heat-diff", diff)]
+}
+
+fn render_aovs(scene: &Scene, cfg: &Config) -> Outputs {
+    let frame = render(scene,
+                       cfg,
+                       AovSample::background(),
+                       |hit, r, _| AovSample::new(scene, &hit, &r));
+    aov::images(&frame, cfg)
+}
+
+fn render_normals(scene: &Scene, cfg: &Config) -> Outputs {
+    let frame = render(scene, cfg, None, |hit, _, _| if hit.is_valid() {
+        Some(scene.geometric_normal(&hit))
+    } else {
+        None
+    });
+    let image: Box<ToImage> = Box::new(VectorMap {
+                                           frame,
+                                           range: Some((-1.0, 1.0)),
+                                           channel_names: ["X", "Y", "Z"],
+                                       });
+    vec![("normal", image)]
+}
+
+fn render_facing_ratio(scene: &Scene, cfg: &Config) -> Outputs {
+    let frame = render(scene, cfg, None, |hit, r, _| if hit.is_valid() {
+        Some(scene.geometric_normal(&hit).dot(r.d).abs())
+    } else {
+        None
+    });
+    let style = Style { fixed_range: Some((0.0, 1.0)), ..cfg.style(ColorMap::Grey) };
+    let image: Box<ToImage> = Box::new(ScalarMap(frame, style));
+    vec![("facing-ratio", image)]
+}
+
+fn render_headlight(scene: &Scene, cfg: &Config) -> Outputs {
+    const AMBIENT: f32 = 0.1;
+    let frame = render(scene, cfg, None, |hit, r, _| {
+        if !hit.is_valid() {
+            return None;
+        }
+        // Back faces are determined by the geometry, but shaded smoothly like front faces.
+        let back_face = scene.geometric_normal(&hit).dot(r.d) > 0.0;
+        let albedo = if back_face {
+            vec3(0.8, 0.2, 0.2)
+        } else {
+            scene.albedo(&hit)
+        };
+        let cos_theta = scene.shading_normal(&hit).dot(r.d).abs();
+        Some(albedo * (AMBIENT + (1.0 - AMBIENT) * cos_theta))
+    });
+    let image: Box<ToImage> = Box::new(VectorMap {
+                                           frame,
+                                           range: Some((0.0, 1.0)),
+                                           channel_names: ["R", "G", "B"],
+                                       });
+    vec![("headlight", image)]
+}
+
+fn render_triangle_ids(scene: &Scene, cfg: &Config) -> Outputs {
+    let frame = render(scene, cfg, None, |hit, _, _| if hit.is_valid() {
+        Some(scene.original_tri_id(&hit))
+    } else {
+        None
+    });
+    let image: Box<ToImage> = Box::new(IdMap(frame));
+    vec![("tri-id", image)]
+}
+
+fn render_leaf_ids(scene: &Scene, cfg: &Config) -> Outputs {
+    let frame = render(scene, cfg, None, |hit, _, _| if hit.is_valid() {
+        Some(scene.leaf_id(&hit))
+    } else {
+        None
+    });
+    let image: Box<ToImage> = Box::new(IdMap(frame));
+    vec![("leaf-id", image)]
+}
+
+fn render_ao(scene: &Scene, cfg: &Config) -> Outputs {
+    let max_distance = cfg.ao_distance.unwrap_or_else(|| {
+        let bb = scene.bbox();
+        0.1 * (bb.max() - bb.min()).magnitude()
+    });
+    let frame = render(scene, cfg, None, |hit, r, rng| if hit.is_valid() {
+        Some(integrator::ambient_occlusion(scene, &hit, &r, cfg.ao_samples, max_distance, rng))
+    } else {
+        None
+    });
+    let style = Style { fixed_range: Some((0.0, 1.0)), ..cfg.style(ColorMap::Grey) };
+    let image: Box<ToImage> = Box::new(ScalarMap(frame, style));
+    vec![("ao", image)]
+}
+
+fn render_path(scene: &Scene, cfg: &Config) -> Outputs {
+    let frame = render(scene,
+                       cfg,
+                       vec3(0.0, 0.0, 0.0),
+                       |hit, r, rng| integrator::path_trace(scene, hit, r, cfg, rng));
+    let image: Box<ToImage> = Box::new(RadianceMap(frame));
+    vec![("path", image)]
+}
+
+/// Write all outputs of a render. A single output goes to `path`.
+/// Multiple outputs are written as layers of a single file if the format is EXR,
+/// otherwise each one is written to a separate file whose name is derived from `path`.
+/// Pixels where nothing was hit show the `backdrop`, if given, in the 8-bit formats.
+fn write_outputs(outputs: Outputs,
+                 path: &Path,
+                 cfg: &Config,
+                 backdrop: Option<&Backdrop>)
+                 -> io::Result<()> {
+    let format = Format::from_path(path).unwrap();
+    let save = |image: &ToImage, path: &Path| if format.is_hdr() {
+        image.to_float_image().save(path)
+    } else {
+        image.to_image(backdrop).save(path)
+    };
+    if outputs.len() == 1 {
+        return save(&*outputs[0].1, path);
+    }
+    if format == Format::Exr {
+        let mut channels = Vec::new();
+        for (name, image) in outputs {
+            for mut channel in image.to_float_image().into_channels() {
+                channel.name = format!("{}.{}", name, channel.name);
+                channels.push(channel);
+            }
+        }
+        return FloatImage::new(cfg.image_width, cfg.image_height, channels).save(path);
+    }
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let ext = path.extension().unwrap().to_string_lossy();
+    for &(name, ref image) in &outputs {
+        save(&**image, &path.with_file_name(format!("{}.{}.{}", stem, name, ext)))?;
+    }
+    Ok(())
 }
 
 fn main() {
@@ -90,19 +374,56 @@ fn main() {
         rayon::initialize(rayon_cfg).unwrap();
     }
 
+    let has_delta_lights = !cfg.delta_lights.is_empty() || cfg.lights_file.is_some();
+    match cfg.render_kind {
+        RenderKind::PathTrace => {}
+        _ if has_delta_lights => println!("warning: --light and --lights only affect --kind path"),
+        _ => {}
+    }
+
     let scene = Scene::new(&cfg);
-    let render: fn(_, _) -> _ = match cfg.render_kind {
-        RenderKind::Depthmap => render_depthmap,
-        RenderKind::Heatmap => render_heatmap,
+    // The second BVH isn't part of the rendering time.
+    let other_scene = match cfg.render_kind {
+        RenderKind::HeatDiff => {
+            let other_cfg = cfg.diff_bvh_config();
+            Some(print_timing("building second BVH", || scene.with_bvh(&other_cfg)))
+        }
+        _ => None,
+    };
+    let render = || match cfg.render_kind {
+        RenderKind::Depthmap => render_depthmap(&scene, &cfg),
+        RenderKind::Heatmap => render_heatmap(&scene, &cfg),
+        RenderKind::HeatDiff => render_heat_diff(&scene, other_scene.as_ref().unwrap(), &cfg),
+        RenderKind::Aovs => render_aovs(&scene, &cfg),
+        RenderKind::Normals => render_normals(&scene, &cfg),
+        RenderKind::FacingRatio => render_facing_ratio(&scene, &cfg),
+        RenderKind::Headlight => render_headlight(&scene, &cfg),
+        RenderKind::TriangleIds => render_triangle_ids(&scene, &cfg),
+        RenderKind::LeafIds => render_leaf_ids(&scene, &cfg),
+        RenderKind::AmbientOcclusion => render_ao(&scene, &cfg),
+        RenderKind::PathTrace => render_path(&scene, &cfg),
     };
-    let (frame, t) = measure_and_print_time("rendering", || render(&scene, &cfg));
-    let output_file = cfg.output_file.display().to_string();
-    print_timing("creating BMP",
-                 move || frame.to_bmp().save(&output_file).unwrap());
-    let rays_tested = scene.rays_tested();
+    let (outputs, t) = measure_and_print_time("rendering", render);
+    // The path tracer renders the environment itself.
+    let backdrop = match cfg.render_kind {
+        RenderKind::PathTrace => None,
+        _ if cfg.env_map.is_some() => {
+            Some(print_timing("rendering backdrop", || render_backdrop(&scene, &cfg)))
+        }
+        _ => None,
+    };
+    print_timing("writing image", || {
+        write_outputs(outputs, &cfg.output_file, &cfg, backdrop.as_ref()).unwrap()
+    });
+    let rays_tested = scene.rays_tested() + other_scene.as_ref().map_or(0, |s| s.rays_tested());
+    if rays_tested == 0 {
+        return;
+    }
     let seconds = f64(t.as_secs()) + f64(t.subsec_nanos()) / 1e9;
     let mrays = f64(rays_tested) / 1e6;
-    let time_per_ray = t / u32(rays_tested).unwrap();
+    // With many samples per pixel, the number of rays can exceed `u32`, so `t` can't be divided
+    // by it directly.
+    let time_per_ray = Duration::new(0, u32((seconds * 1e9 / f64(rays_tested)).round()).unwrap());
     println!("{:.2}M rays @ {:.3} Mray/s ({:} per ray)",
              mrays,
              mrays / seconds,
@@ -0,0 +1,104 @@
+use bsdf::{Bsdf, Conductor, Lambert, RoughDielectric, SmoothDielectric};
+use cgmath::{Vector3, vec3};
+use obj::raw::material::{Material as MtlMaterial, MtlColor};
+use std::sync::Arc;
+use texture::Texture;
+
+/// Surface properties of a triangle, as described by a Wavefront MTL material.
+#[derive(Clone)]
+pub struct Material {
+    /// Diffuse reflectance (Kd).
+    pub diffuse: Vector3<f32>,
+    /// Texture that the diffuse reflectance is multiplied with (map_Kd).
+    pub diffuse_map: Option<Arc<Texture>>,
+    /// Specular reflectance (Ks).
+    pub specular: Vector3<f32>,
+    /// Phong exponent of the specular highlight (Ns).
+    pub shininess: f32,
+    /// Emitted radiance (Ke).
+    pub emission: Vector3<f32>,
+    /// Index of refraction of transparent materials (Ni).
+    pub ior: f32,
+    /// The MTL illumination model (illum).
+    pub illum: u32,
+}
+
+impl Default for Material {
+    /// A grey diffuse surface, used for triangles without a material.
+    fn default() -> Self {
+        Material {
+            diffuse: vec3(0.8, 0.8, 0.8),
+            diffuse_map: None,
+            specular: vec3(0.0, 0.0, 0.0),
+            shininess: 0.0,
+            emission: vec3(0.0, 0.0, 0.0),
+            ior: 1.5,
+            illum: 1,
+        }
+    }
+}
+
+impl Material {
+    /// Convert a material from an MTL file. Missing statements take the default values.
+    /// Textures are loaded by `load_texture`, which gets the file name from the MTL file.
+    pub fn from_mtl<F>(name: &str, mtl: &MtlMaterial, mut load_texture: F) -> Self
+        where F: FnMut(&str) -> Option<Arc<Texture>>
+    {
+        let default = Material::default();
+        let color = |c: &Option<MtlColor>, default: Vector3<f32>| match *c {
+            Some(MtlColor::Rgb(r, g, b)) => vec3(r, g, b),
+            Some(ref other) => {
+                println!("material {}: ignoring unsupported color {:?}", name, other);
+                default
+            }
+            None => default,
+        };
+        Material {
+            diffuse: color(&mtl.diffuse, default.diffuse),
+            diffuse_map: mtl.diffuse_map.as_ref().and_then(|map| load_texture(&map.file)),
+            specular: color(&mtl.specular, default.specular),
+            shininess: mtl.specular_exponent.unwrap_or(default.shininess),
+            emission: color(&mtl.emissive, default.emission),
+            ior: mtl.optical_density.unwrap_or(default.ior),
+            illum: mtl.illumination_model.unwrap_or(default.illum),
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission.x > 0.0 || self.emission.y > 0.0 || self.emission.z > 0.0
+    }
+
+    /// The GGX roughness corresponding to the Phong exponent, `None` for perfectly smooth.
+    fn roughness(&self) -> Option<f32> {
+        if self.shininess > 0.0 {
+            Some((2.0 / (self.shininess + 2.0)).sqrt())
+        } else {
+            None
+        }
+    }
+
+    /// The scattering model for this material, with the given (possibly textured) albedo.
+    /// The MTL illumination model selects the kind of surface:
+    /// 3 and 5 are metals reflecting with Ks, 4, 6, 7 and 9 are glass with index Ni,
+    /// everything else is diffuse (the specular highlight of illum 2 is ignored).
+    pub fn bsdf(&self, albedo: Vector3<f32>) -> Box<Bsdf> {
+        match self.illum {
+            3 | 5 => {
+                Box::new(Conductor {
+                             specular: self.specular,
+                             alpha: self.roughness().unwrap_or(MIN_ROUGHNESS),
+                         })
+            }
+            4 | 6 | 7 | 9 => {
+                match self.roughness() {
+                    Some(alpha) => Box::new(RoughDielectric { ior: self.ior, alpha }),
+                    None => Box::new(SmoothDielectric { ior: self.ior }),
+                }
+            }
+            _ => Box::new(Lambert { albedo }),
+        }
+    }
+}
+
+/// Perfectly smooth metals are approximated by a very low roughness.
+const MIN_ROUGHNESS: f32 = 1e-3;
@@ -0,0 +1,511 @@
+use bmp;
+use cast::{f32, i32, u64, usize};
+use png::{self, HasParameters};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pixel {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+pub const BLUE: Pixel = Pixel { r: 0, g: 0, b: 255 };
+
+/// An 8-bit RGB image that can be written in any of the supported formats.
+pub struct Image {
+    width: u32,
+    height: u32,
+    /// Row by row, top to bottom.
+    pixels: Vec<Pixel>,
+}
+
+/// A single channel of raw pixel values.
+pub struct Channel {
+    pub name: String,
+    pub values: Values,
+}
+
+/// The values of a channel, row by row, top to bottom.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Values {
+    Float(Vec<f32>),
+    /// Integers such as IDs, which floats can't store exactly beyond 2^24.
+    /// EXR stores them losslessly, PFM only as floats.
+    Uint(Vec<u32>),
+}
+
+impl Values {
+    pub fn len(&self) -> usize {
+        match *self {
+            Values::Float(ref values) => values.len(),
+            Values::Uint(ref values) => values.len(),
+        }
+    }
+
+    /// The `i`-th value, converted to a float if necessary.
+    pub fn float_at(&self, i: usize) -> f32 {
+        match *self {
+            Values::Float(ref values) => values[i],
+            Values::Uint(ref values) => f32(values[i]),
+        }
+    }
+}
+
+/// An image made of float channels, for storing raw data without quantization.
+pub struct FloatImage {
+    width: u32,
+    height: u32,
+    channels: Vec<Channel>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Bmp,
+    Png,
+    /// Portable float map, for one or three channels.
+    Pfm,
+    /// OpenEXR, uncompressed scanline image with 32 bit float channels.
+    Exr,
+}
+
+impl Format {
+    /// Determine the format from the file extension.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let ext = path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase());
+        match ext.as_ref().map(|ext| &ext[..]) {
+            Some("bmp") => Some(Format::Bmp),
+            Some("png") => Some(Format::Png),
+            Some("pfm") => Some(Format::Pfm),
+            Some("exr") => Some(Format::Exr),
+            _ => None,
+        }
+    }
+
+    /// Whether this format stores float values rather than 8-bit colors.
+    pub fn is_hdr(&self) -> bool {
+        match *self {
+            Format::Bmp | Format::Png => false,
+            Format::Pfm | Format::Exr => true,
+        }
+    }
+}
+
+fn unsupported_format(path: &Path) -> io::Error {
+    let msg = format!("unsupported image format: {}", path.display());
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![Pixel { r: 0, g: 0, b: 0 }; usize(width) * usize(height)],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, px: Pixel) {
+        let i = usize(y) * usize(self.width) + usize(x);
+        self.pixels[i] = px;
+    }
+
+    /// Write the image to `path`, in the format indicated by the file extension.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        match Format::from_path(path) {
+            Some(Format::Bmp) => self.to_bmp().save(&path.to_string_lossy()),
+            Some(Format::Png) => self.save_png(path),
+            _ => Err(unsupported_format(path)),
+        }
+    }
+
+    fn to_bmp(&self) -> bmp::Image {
+        let mut img = bmp::Image::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let px = self.pixels[usize(y) * usize(self.width) + usize(x)];
+                img.set_pixel(x, y, bmp::Pixel { r: px.r, g: px.g, b: px.b });
+            }
+        }
+        img
+    }
+
+    fn save_png(&self, path: &Path) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+        let mut data = Vec::with_capacity(3 * self.pixels.len());
+        for px in &self.pixels {
+            data.extend_from_slice(&[px.r, px.g, px.b]);
+        }
+        let mut writer = encoder.write_header().map_err(png_error)?;
+        writer.write_image_data(&data).map_err(png_error)
+    }
+}
+
+fn png_error(e: png::EncodingError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+impl FloatImage {
+    pub fn new(width: u32, height: u32, channels: Vec<Channel>) -> Self {
+        for c in &channels {
+            assert_eq!(c.values.len(), usize(width) * usize(height));
+        }
+        FloatImage {
+            width,
+            height,
+            channels,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn into_channels(self) -> Vec<Channel> {
+        self.channels
+    }
+
+    /// Read a PFM file, with a single channel "Y" or the three channels "R", "G" and "B".
+    pub fn load_pfm(path: &Path) -> io::Result<Self> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        FloatImage::read_pfm(&data)
+    }
+
+    /// The inverse of `write_pfm`, but also accepts big endian files.
+    fn read_pfm(data: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut pos = 0;
+        let mut header = Vec::new();
+        for _ in 0..4 {
+            while pos < data.len() && (data[pos] as char).is_whitespace() {
+                pos += 1;
+            }
+            let start = pos;
+            while pos < data.len() && !(data[pos] as char).is_whitespace() {
+                pos += 1;
+            }
+            header.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+        }
+        // A single whitespace character separates the header from the pixel data.
+        pos += 1;
+        let names = match &header[0][..] {
+            "PF" => vec!["R", "G", "B"],
+            "Pf" => vec!["Y"],
+            _ => return Err(invalid("not a PFM file")),
+        };
+        let parse_dim = |s: &str| s.parse::<u32>().map_err(|_| invalid("invalid PFM dimensions"));
+        let (width, height) = (parse_dim(&header[1])?, parse_dim(&header[2])?);
+        if width == 0 || height == 0 {
+            return Err(invalid("empty PFM image"));
+        }
+        let scale: f32 = header[3].parse().map_err(|_| invalid("invalid PFM scale"))?;
+        let (w, h) = (usize(width), usize(height));
+        // The dimensions of a corrupt file can be large enough to overflow.
+        let end = (4 * names.len())
+            .checked_mul(w)
+            .and_then(|size| size.checked_mul(h))
+            .and_then(|size| size.checked_add(pos));
+        match end {
+            Some(end) if end <= data.len() => {}
+            _ => return Err(invalid("truncated PFM file")),
+        }
+        let value = |i: usize| {
+            let b = |j: usize| u32::from(data[pos + 4 * i + j]);
+            let bits = if scale < 0.0 {
+                b(0) | b(1) << 8 | b(2) << 16 | b(3) << 24
+            } else {
+                b(0) << 24 | b(1) << 16 | b(2) << 8 | b(3)
+            };
+            <f32>::from_bits(bits)
+        };
+        let channels = names.iter()
+            .enumerate()
+            .map(|(c, name)| {
+                let mut values = Vec::with_capacity(w * h);
+                for y in (0..h).rev() {
+                    for x in 0..w {
+                        values.push(value(names.len() * (y * w + x) + c));
+                    }
+                }
+                Channel {
+                    name: name.to_string(),
+                    values: Values::Float(values),
+                }
+            })
+            .collect();
+        Ok(FloatImage::new(width, height, channels))
+    }
+
+    /// Write the image to `path`, in the format indicated by the file extension.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        // Check the format first, so nothing is created for an unsupported one.
+        let write: fn(&Self, &mut BufWriter<File>) -> io::Result<()> =
+            match Format::from_path(path) {
+                Some(Format::Pfm) => FloatImage::write_pfm,
+                Some(Format::Exr) => FloatImage::write_exr,
+                _ => return Err(unsupported_format(path)),
+            };
+        let mut w = BufWriter::new(File::create(path)?);
+        write(self, &mut w)?;
+        // Dropping the writer would flush it too, but ignore any errors.
+        w.flush()
+    }
+
+    fn write_pfm<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let magic = match self.channels.len() {
+            1 => "Pf",
+            3 => "PF",
+            n => {
+                let msg = format!("PFM can't store {} channels", n);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        // A negative scale means little endian.
+        write!(w, "{}\n{} {}\n-1.0\n", magic, self.width, self.height)?;
+        // PFM stores the rows bottom to top.
+        for y in (0..usize(self.height)).rev() {
+            for x in 0..usize(self.width) {
+                for c in &self.channels {
+                    write_f32(w, c.values.float_at(y * usize(self.width) + x))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_exr<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        const PIXEL_TYPE_UINT: i32 = 0;
+        const PIXEL_TYPE_FLOAT: i32 = 2;
+        const NO_COMPRESSION: u8 = 0;
+        const INCREASING_Y: u8 = 0;
+        // EXR requires the channels to be sorted by name.
+        let mut channels: Vec<&Channel> = self.channels.iter().collect();
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+        let (width, height) = (i32(self.width).unwrap(), i32(self.height).unwrap());
+
+        let mut header = Vec::new();
+        let mut chlist = Vec::new();
+        for c in &channels {
+            write_cstr(&mut chlist, &c.name)?;
+            let pixel_type = match c.values {
+                Values::Float(_) => PIXEL_TYPE_FLOAT,
+                Values::Uint(_) => PIXEL_TYPE_UINT,
+            };
+            write_i32(&mut chlist, pixel_type)?;
+            // pLinear and three reserved bytes, then x and y sampling.
+            chlist.write_all(&[0, 0, 0, 0])?;
+            write_i32(&mut chlist, 1)?;
+            write_i32(&mut chlist, 1)?;
+        }
+        chlist.push(0);
+        write_attribute(&mut header, "channels", "chlist", &chlist)?;
+        write_attribute(&mut header, "compression", "compression", &[NO_COMPRESSION])?;
+        let mut window = Vec::new();
+        for &coord in &[0, 0, width - 1, height - 1] {
+            write_i32(&mut window, coord)?;
+        }
+        write_attribute(&mut header, "dataWindow", "box2i", &window)?;
+        write_attribute(&mut header, "displayWindow", "box2i", &window)?;
+        write_attribute(&mut header, "lineOrder", "lineOrder", &[INCREASING_Y])?;
+        let mut one = Vec::new();
+        write_f32(&mut one, 1.0)?;
+        write_attribute(&mut header, "pixelAspectRatio", "float", &one)?;
+        write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+        write_attribute(&mut header, "screenWindowWidth", "float", &one)?;
+        header.push(0);
+
+        // Magic number and version 2 (single part scanline file).
+        w.write_all(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0])?;
+        w.write_all(&header)?;
+        // Without compression, every chunk holds a single scanline.
+        // Both pixel types take four bytes.
+        let line_size = 4 * channels.len() * usize(self.width);
+        let first_chunk = 8 + header.len() + 8 * usize(self.height);
+        for y in 0..usize(self.height) {
+            write_u64(w, u64(first_chunk + y * (8 + line_size)))?;
+        }
+        for y in 0..usize(self.height) {
+            write_i32(w, i32(y).unwrap())?;
+            write_i32(w, i32(line_size).unwrap())?;
+            for c in &channels {
+                let row = y * usize(self.width)..(y + 1) * usize(self.width);
+                match c.values {
+                    Values::Float(ref values) => {
+                        for &value in &values[row] {
+                            write_f32(w, value)?;
+                        }
+                    }
+                    Values::Uint(ref values) => {
+                        for &value in &values[row] {
+                            write_u32(w, value)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_attribute<W: Write>(w: &mut W, name: &str, ty: &str, value: &[u8]) -> io::Result<()> {
+    write_cstr(w, name)?;
+    write_cstr(w, ty)?;
+    write_i32(w, i32(value.len()).unwrap())?;
+    w.write_all(value)
+}
+
+fn write_cstr<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    let s = CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    w.write_all(s.as_bytes_with_nul())
+}
+
+fn write_u32<W: Write>(w: &mut W, x: u32) -> io::Result<()> {
+    w.write_all(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8])
+}
+
+fn write_u64<W: Write>(w: &mut W, x: u64) -> io::Result<()> {
+    write_u32(w, x as u32)?;
+    write_u32(w, (x >> 32) as u32)
+}
+
+fn write_i32<W: Write>(w: &mut W, x: i32) -> io::Result<()> {
+    write_u32(w, x as u32)
+}
+
+fn write_f32<W: Write>(w: &mut W, x: f32) -> io::Result<()> {
+    write_u32(w, x.to_bits())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str, values: &[f32]) -> Channel {
+        Channel {
+            name: name.to_string(),
+            values: Values::Float(values.to_vec()),
+        }
+    }
+
+    fn le_u32(x: u32) -> Vec<u8> {
+        vec![x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]
+    }
+
+    fn attribute(name: &str, ty: &str, value: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(ty.as_bytes());
+        bytes.push(0);
+        bytes.extend(le_u32(value.len() as u32));
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    #[test]
+    fn pfm_round_trip() {
+        let grey = FloatImage::new(2, 3, vec![channel("Y", &[1.0, -2.5, 0.0, 1e-3, 7.0, 1e10])]);
+        let rgb = FloatImage::new(2,
+                                  1,
+                                  vec![channel("R", &[1.0, 2.0]),
+                                       channel("G", &[3.0, 4.0]),
+                                       channel("B", &[5.0, 6.0])]);
+        for img in &[grey, rgb] {
+            let mut data = Vec::new();
+            img.write_pfm(&mut data).unwrap();
+            let read = FloatImage::read_pfm(&data).unwrap();
+            assert_eq!((read.width(), read.height()), (img.width(), img.height()));
+            assert_eq!(read.channels.len(), img.channels.len());
+            for (a, b) in read.channels.iter().zip(&img.channels) {
+                assert_eq!(a.name, b.name);
+                assert_eq!(a.values, b.values);
+            }
+        }
+    }
+
+    #[test]
+    fn pfm_rejects_truncated_and_empty_files() {
+        assert!(FloatImage::read_pfm(b"Pf\n2 2\n-1.0\n\0\0\0\0").is_err());
+        assert!(FloatImage::read_pfm(b"Pf\n0 2\n-1.0\n").is_err());
+        assert!(FloatImage::read_pfm(b"P6\n1 1\n255\n\0\0\0").is_err());
+    }
+
+    #[test]
+    fn exr_uint_channel_is_exact() {
+        let id = (1 << 24) + 1;
+        let ids = Channel {
+            name: "id".to_string(),
+            values: Values::Uint(vec![id]),
+        };
+        let mut data = Vec::new();
+        FloatImage::new(1, 1, vec![ids]).write_exr(&mut data).unwrap();
+        // The pixel type UINT follows the channel name.
+        let chlist = b"id\0\0\0\0\0";
+        assert!(data.windows(chlist.len()).any(|w| w == chlist));
+        assert_eq!(&data[data.len() - 4..], &le_u32(id)[..]);
+    }
+
+    #[test]
+    fn exr_single_channel_layout() {
+        let img = FloatImage::new(2, 2, vec![channel("Y", &[1.0, 2.0, 3.0, 4.0])]);
+        let mut data = Vec::new();
+        img.write_exr(&mut data).unwrap();
+
+        let mut chlist = b"Y\0".to_vec();
+        chlist.extend(le_u32(2));
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend(le_u32(1));
+        chlist.extend(le_u32(1));
+        chlist.push(0);
+        let mut window = Vec::new();
+        for &coord in &[0, 0, 1, 1] {
+            window.extend(le_u32(coord));
+        }
+        let one = le_u32(1.0f32.to_bits());
+        let mut header = Vec::new();
+        header.extend(attribute("channels", "chlist", &chlist));
+        header.extend(attribute("compression", "compression", &[0]));
+        header.extend(attribute("dataWindow", "box2i", &window));
+        header.extend(attribute("displayWindow", "box2i", &window));
+        header.extend(attribute("lineOrder", "lineOrder", &[0]));
+        header.extend(attribute("pixelAspectRatio", "float", &one));
+        header.extend(attribute("screenWindowCenter", "v2f", &[0; 8]));
+        header.extend(attribute("screenWindowWidth", "float", &one));
+        header.push(0);
+
+        let mut expected = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+        expected.extend_from_slice(&header);
+        // The offset table: each scanline chunk is its y coordinate, its size and two floats.
+        let first_chunk = (8 + header.len() + 2 * 8) as u32;
+        for &offset in &[first_chunk, first_chunk + 16] {
+            expected.extend(le_u32(offset));
+            expected.extend(le_u32(0));
+        }
+        for (y, row) in [[1.0f32, 2.0], [3.0, 4.0]].iter().enumerate() {
+            expected.extend(le_u32(y as u32));
+            expected.extend(le_u32(8));
+            for &value in row {
+                expected.extend(le_u32(value.to_bits()));
+            }
+        }
+        assert_eq!(data, expected);
+    }
+}
@@ -0,0 +1,120 @@
+use cast::{f32, f64, u64, usize};
+use cgmath::Vector3;
+use geom::orthonormal_basis;
+use std::cmp::{self, Ordering};
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+/// A small PCG32 random number generator, see http://www.pcg-random.org
+/// Every pixel gets its own stream so that renders are reproducible regardless of how the
+/// work is split between threads.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+    inc: u64,
+}
+
+const PCG_MULTIPLIER: u64 = 6364136223846793005;
+
+impl Rng {
+    pub fn new(seed: u64, stream: u64) -> Rng {
+        let mut rng = Rng {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn for_pixel(seed: u64, x: u32, y: u32) -> Rng {
+        Rng::new(seed, (u64(x) << 32) | u64(y))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(PCG_MULTIPLIER).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// Returns a number uniformly distributed in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        // Only use 24 bits so that every value is exactly representable.
+        f32(self.next_u32() >> 8) / 16777216.0
+    }
+
+    pub fn next_2d(&mut self) -> (f32, f32) {
+        let u = self.next_f32();
+        (u, self.next_f32())
+    }
+}
+
+/// Map a point from the unit square to the unit disk, preserving relative areas.
+/// This is Shirley and Chiu's concentric mapping, which distorts strata less than
+/// the naive polar mapping.
+pub fn concentric_disk((u, v): (f32, f32)) -> (f32, f32) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, phi) = if a.abs() > b.abs() {
+        (a, FRAC_PI_4 * (b / a))
+    } else {
+        (b, FRAC_PI_2 - FRAC_PI_4 * (a / b))
+    };
+    (r * phi.cos(), r * phi.sin())
+}
+
+/// Sample a direction in the hemisphere around the unit vector `n`, with density proportional
+/// to the cosine of the angle to `n` (i.e., cos(theta) / pi per unit solid angle).
+pub fn cosine_hemisphere(n: Vector3<f32>, u: (f32, f32)) -> Vector3<f32> {
+    let (x, y) = concentric_disk(u);
+    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+    let (t, b) = orthonormal_basis(n);
+    t * x + b * y + n * z
+}
+
+/// Pick an index with probability proportional to its weight, given the cumulative sums of the
+/// weights normalized to 1 (i.e., `cdf[i]` is the probability of picking any index up to `i`).
+pub fn sample_discrete(cdf: &[f32], u: f32) -> usize {
+    let i = match cdf.binary_search_by(|&c| if c <= u {
+                                           Ordering::Less
+                                       } else {
+                                           Ordering::Greater
+                                       }) {
+        Ok(i) | Err(i) => i,
+    };
+    // Rounding can make the last entry slightly less than 1.
+    cmp::min(i, cdf.len() - 1)
+}
+
+/// The weight of a sample taken from a strategy with density `pdf` when it is combined with
+/// another strategy with density `other_pdf`, according to Veach's power heuristic (beta = 2).
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+/// Generate `n` stratified sample positions in the unit square.
+/// The first k² samples (k = floor(sqrt(n))) are jittered within the cells of a k×k grid,
+/// any remaining samples are uniformly distributed over the whole square.
+/// A single sample is placed in the center, so that single-sample renders are not noisy.
+pub fn stratified_2d(n: u32, rng: &mut Rng) -> Vec<(f32, f32)> {
+    if n == 1 {
+        return vec![(0.5, 0.5)];
+    }
+    let k = f64(n).sqrt().floor() as u32;
+    let mut samples = Vec::with_capacity(usize(n));
+    for i in 0..k {
+        for j in 0..k {
+            let (u, v) = rng.next_2d();
+            samples.push(((f32(i) + u) / f32(k), (f32(j) + v) / f32(k)));
+        }
+    }
+    for _ in k * k..n {
+        samples.push(rng.next_2d());
+    }
+    samples
+}
@@ -1,28 +1,119 @@
 use super::{Config, print_timing};
+use beebox::Aabb;
+use bsdf::Bsdf;
 use bvh::{self, Bvh};
-use cast::usize;
-use cgmath::{Vector3, vec3};
+use cast::{u32, usize};
+use cgmath::{ElementWise, InnerSpace, Vector2, Vector3, vec2, vec3};
+use envmap::{EnvMap, Environment};
 use geom::{Hit, Ray, Tri, TriSliceExt};
-use obj;
+use light::{self, AreaLights, DeltaLight, LightSample};
+use material::Material;
+use obj::raw::{self, RawObj};
+use obj::raw::object::Polygon;
+use rayon::prelude::*;
+use sampling::Rng;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
+use std::process;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use texture::{Texture, Wrap};
 
 pub struct Scene {
+    /// The triangles, in the order of the BVH leaves.
     pub tris: Vec<Tri>,
+    /// The index of every triangle in the OBJ file (after splitting polygons into triangles).
+    /// Unlike the index into `tris`, this doesn't depend on how the BVH was built.
+    original_tri_ids: Vec<u32>,
+    /// The index into `materials` of every triangle.
+    material_ids: Vec<u32>,
+    materials: Vec<Material>,
+    /// The unit normals at the vertices of every triangle, for smooth shading.
+    vertex_normals: Vec<[Vector3<f32>; 3]>,
+    /// The texture coordinates at the vertices of every triangle, zero if the file has none.
+    tex_coords: Vec<[Vector2<f32>; 3]>,
+    /// The triangles with an emissive material.
+    lights: AreaLights,
+    environment: Arc<Environment>,
+    delta_lights: Vec<DeltaLight>,
     bvh: Bvh,
     rays_tested: AtomicUsize,
 }
 
+/// The contents of an OBJ file, before the BVH is built.
+struct Mesh {
+    tris: Vec<Tri>,
+    original_tri_ids: Vec<u32>,
+    material_ids: Vec<u32>,
+    materials: Vec<Material>,
+    vertex_normals: Vec<[Vector3<f32>; 3]>,
+    tex_coords: Vec<[Vector2<f32>; 3]>,
+}
+
 impl Scene {
     pub fn new(cfg: &Config) -> Self {
+        // The environment map is loaded first, so a bad file is reported without waiting for
+        // the (usually much larger) OBJ to load.
+        let environment = match cfg.env_map {
+            Some(ref path) => {
+                let desc = format!("loading environment map: {}", path.display());
+                match print_timing(&desc, || EnvMap::load(path)) {
+                    Ok(map) => Environment::Map(map),
+                    Err(e) => {
+                        println!("can't load environment map {}: {}", path.display(), e);
+                        process::exit(1);
+                    }
+                }
+            }
+            None => Environment::Constant(cfg.sky_color),
+        };
         let desc = format!("loading OBJ: {}", cfg.input_file.display());
-        let mut tris = print_timing(&desc, || read_obj(&cfg.input_file));
-        normalize(&mut tris);
-        let (bvh, tris) = bvh::construct(&tris, cfg);
+        let mesh = print_timing(&desc, || read_obj(&cfg.input_file, cfg.texture_wrap));
+        let mut delta_lights = cfg.delta_lights.clone();
+        if let Some(ref path) = cfg.lights_file {
+            // The file was already parsed once to validate the command line.
+            delta_lights.extend(light::read_lights_file(path).unwrap());
+        }
+        Scene::build(mesh, Arc::new(environment), delta_lights, cfg)
+    }
+
+    /// Create a scene with the same triangles but a BVH built with different parameters.
+    pub fn with_bvh(&self, cfg: &Config) -> Self {
+        let mesh = Mesh {
+            tris: self.tris.clone(),
+            original_tri_ids: self.original_tri_ids.clone(),
+            material_ids: self.material_ids.clone(),
+            materials: self.materials.clone(),
+            vertex_normals: self.vertex_normals.clone(),
+            tex_coords: self.tex_coords.clone(),
+        };
+        Scene::build(mesh, self.environment.clone(), self.delta_lights.clone(), cfg)
+    }
+
+    fn build(mesh: Mesh,
+             environment: Arc<Environment>,
+             delta_lights: Vec<DeltaLight>,
+             cfg: &Config)
+             -> Self {
+        let (bvh, order) = bvh::construct(&mesh.tris, cfg);
+        let tris: Vec<Tri> = order.par_iter().map(|&i| mesh.tris[i].clone()).collect();
+        let material_ids: Vec<u32> = order.iter().map(|&i| mesh.material_ids[i]).collect();
+        let lights = {
+            let materials = &mesh.materials;
+            AreaLights::new(&tris, |i| materials[usize(material_ids[i])].is_emissive())
+        };
         Scene {
             tris,
+            original_tri_ids: order.iter().map(|&i| mesh.original_tri_ids[i]).collect(),
+            material_ids,
+            materials: mesh.materials,
+            vertex_normals: order.iter().map(|&i| mesh.vertex_normals[i]).collect(),
+            tex_coords: order.iter().map(|&i| mesh.tex_coords[i]).collect(),
+            lights,
+            environment,
+            delta_lights,
             bvh,
             rays_tested: AtomicUsize::new(0),
         }
@@ -33,37 +124,320 @@ impl Scene {
         bvh::traverse(&self.tris, &self.bvh, r)
     }
 
+    /// Whether anything blocks the ray between `r.t_min` and `r.t_max`, e.g. for shadow rays.
+    pub fn occluded(&self, r: &Ray) -> bool {
+        self.rays_tested.fetch_add(1, Ordering::SeqCst);
+        bvh::occluded(&self.tris, &self.bvh, r)
+    }
+
+    /// The normal of the triangle's plane at a (valid) hit.
+    pub fn geometric_normal(&self, hit: &Hit) -> Vector3<f32> {
+        self.tris[usize(hit.tri_id)].normal()
+    }
+
+    /// The interpolated vertex normal at a (valid) hit, for shading.
+    /// Unlike the geometric normal, this varies smoothly over curved surfaces.
+    /// Falls back to the geometric normal where the vertex normals cancel out.
+    pub fn shading_normal(&self, hit: &Hit) -> Vector3<f32> {
+        let n = &self.vertex_normals[usize(hit.tri_id)];
+        let ns = (n[0] * hit.u + n[1] * hit.v + n[2] * hit.w).normalize();
+        if ns.x.is_finite() {
+            ns
+        } else {
+            self.geometric_normal(hit)
+        }
+    }
+
+    /// The interpolated texture coordinates at a (valid) hit.
+    pub fn tex_coords(&self, hit: &Hit) -> Vector2<f32> {
+        let t = &self.tex_coords[usize(hit.tri_id)];
+        t[0] * hit.u + t[1] * hit.v + t[2] * hit.w
+    }
+
+    /// The diffuse reflectance at a (valid) hit, including the material's texture.
+    pub fn albedo(&self, hit: &Hit) -> Vector3<f32> {
+        let material = self.material(hit);
+        match material.diffuse_map {
+            Some(ref map) => material.diffuse.mul_element_wise(map.lookup(self.tex_coords(hit))),
+            None => material.diffuse,
+        }
+    }
+
+    /// The scattering model at a (valid) hit.
+    pub fn bsdf(&self, hit: &Hit) -> Box<Bsdf> {
+        self.material(hit).bsdf(self.albedo(hit))
+    }
+
+    /// The position of a (valid) hit, interpolated from the triangle's vertices.
+    /// This is more accurate than evaluating the ray at the hit distance.
+    pub fn hit_point(&self, hit: &Hit) -> Vector3<f32> {
+        let tri = &self.tris[usize(hit.tri_id)];
+        tri.a * hit.u + tri.b * hit.v + tri.c * hit.w
+    }
+
+    /// The index of the triangle that was hit in the OBJ file, independent of the BVH.
+    pub fn original_tri_id(&self, hit: &Hit) -> u32 {
+        self.original_tri_ids[usize(hit.tri_id)]
+    }
+
+    /// The material of the triangle that was hit.
+    pub fn material(&self, hit: &Hit) -> &Material {
+        self.tri_material(hit.tri_id)
+    }
+
+    pub fn tri_material(&self, tri_id: u32) -> &Material {
+        &self.materials[usize(self.material_ids[usize(tri_id)])]
+    }
+
+    /// The light arriving from directions in which rays don't hit the scene.
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// The solid angle density with which `sample_light` samples the point hit by `r`,
+    /// as seen from the ray origin. Zero if the triangle isn't a light.
+    pub fn light_pdf(&self, hit: &Hit, r: &Ray) -> f32 {
+        if !self.material(hit).is_emissive() {
+            return 0.0;
+        }
+        let cos_light = self.geometric_normal(hit).dot(r.d.normalize()).abs();
+        let dist2 = hit.t * hit.t * r.d.magnitude2();
+        if cos_light > 0.0 {
+            self.lights.pdf() * dist2 / cos_light
+        } else {
+            0.0
+        }
+    }
+
+    /// Sample a point on the emissive triangles, uniformly by area.
+    /// Returns `None` if there are no emissive triangles.
+    pub fn sample_light(&self, rng: &mut Rng) -> Option<LightSample> {
+        self.lights.sample(&self.tris, rng)
+    }
+
+    /// The point, spot and directional lights, which aren't part of the geometry.
+    pub fn delta_lights(&self) -> &[DeltaLight] {
+        &self.delta_lights
+    }
+
+    /// The index of the BVH leaf containing the triangle that was hit.
+    pub fn leaf_id(&self, hit: &Hit) -> u32 {
+        self.bvh.leaf_id(hit.tri_id)
+    }
+
     pub fn rays_tested(&self) -> usize {
         self.rays_tested.load(Ordering::SeqCst)
     }
-}
 
-fn normalize(tris: &mut [Tri]) {
-    let bb = tris.bbox();
-    let (min, max) = (bb.min(), bb.max());
-    let center = (min + max) / 2.0;
-    // This heuristically moves the model such that it's probably within view.
-    let displace = center + vec3(0.0, 0.0, (min.z - max.z).abs());
-    for tri in tris {
-        tri.a -= displace;
-        tri.b -= displace;
-        tri.c -= displace;
+    pub fn bbox(&self) -> Aabb {
+        self.tris.bbox()
     }
 }
 
-fn read_obj(path: &Path) -> Vec<Tri> {
+fn read_obj(path: &Path, wrap: Wrap) -> Mesh {
     let read = BufReader::new(File::open(path).unwrap());
-    let o = obj::load_obj::<obj::Position, _>(read).unwrap();
-    o.indices
-        .chunks(3)
-        .map(|chunk| {
-            assert!(chunk.len() == 3);
-            let (i, j, k) = (usize(chunk[0]), usize(chunk[1]), usize(chunk[2]));
-            Tri {
-                a: Vector3::from(o.vertices[i].position),
-                b: Vector3::from(o.vertices[j].position),
-                c: Vector3::from(o.vertices[k].position),
+    let obj = raw::parse_obj(read).unwrap();
+    let (materials, material_names) = read_material_libraries(path, &obj, wrap);
+
+    // `usemtl` statements group the polygons by material.
+    // Polygons before the first `usemtl` get the default material.
+    let mut polygon_materials = vec![0; obj.polygons.len()];
+    for (name, group) in &obj.meshes {
+        let id = match material_names.get(name) {
+            Some(&id) => id,
+            None => {
+                println!("unknown material {}, using the default material", name);
+                0
+            }
+        };
+        for range in &group.polygons {
+            for m in &mut polygon_materials[range.start..range.end] {
+                *m = id;
+            }
+        }
+    }
+
+    let position = |i: usize| {
+        let (x, y, z, _) = obj.positions[i];
+        vec3(x, y, z)
+    };
+    let normal = |i: usize| {
+        let (x, y, z) = obj.normals[i];
+        vec3(x, y, z).normalize()
+    };
+    let tex_coord = |i: usize| {
+        let (u, v, _) = obj.tex_coords[i];
+        vec2(u, v)
+    };
+    let mut tris = Vec::with_capacity(obj.polygons.len());
+    let mut tri_vertices = Vec::with_capacity(obj.polygons.len());
+    let mut material_ids = Vec::with_capacity(obj.polygons.len());
+    for (polygon, &material) in obj.polygons.iter().zip(&polygon_materials) {
+        let vertices: Vec<ObjVertex> = match *polygon {
+            Polygon::P(ref vs) => vs.iter().map(|&p| ObjVertex::new(p, None, None)).collect(),
+            Polygon::PT(ref vs) => {
+                vs.iter().map(|&(p, t)| ObjVertex::new(p, Some(t), None)).collect()
+            }
+            Polygon::PN(ref vs) => {
+                vs.iter().map(|&(p, n)| ObjVertex::new(p, None, Some(n))).collect()
+            }
+            Polygon::PTN(ref vs) => {
+                vs.iter().map(|&(p, t, n)| ObjVertex::new(p, Some(t), Some(n))).collect()
+            }
+        };
+        // Polygons with more than three vertices are split into a fan of triangles.
+        for i in 1..vertices.len().saturating_sub(1) {
+            let (a, b, c) = (vertices[0], vertices[i], vertices[i + 1]);
+            tris.push(Tri {
+                          a: position(a.position),
+                          b: position(b.position),
+                          c: position(c.position),
+                      });
+            tri_vertices.push([a, b, c]);
+            material_ids.push(material);
+        }
+    }
+
+    // Vertices without a normal in the file get the average normal of the adjacent triangles.
+    let smooth_normals = angle_weighted_normals(obj.positions.len(), &tris, &tri_vertices);
+    let vertex_normals = tri_vertices.iter()
+        .zip(&tris)
+        .map(|(vertices, tri)| {
+            let mut normals = [vec3(0.0, 0.0, 0.0); 3];
+            for (n, v) in normals.iter_mut().zip(vertices) {
+                *n = match v.normal {
+                    Some(i) => normal(i),
+                    None => smooth_normals[v.position],
+                };
+                // The normal is NaN if the file gives a zero normal, or if the vertex is only
+                // used by degenerate triangles or the adjacent normals cancel out. Fall back to
+                // the geometric normal then. Degenerate triangles don't have one either, but
+                // they have no area and are never hit, so any unit vector will do.
+                if !n.x.is_finite() {
+                    let ng = tri.normal();
+                    *n = if ng.x.is_finite() { ng } else { vec3(0.0, 0.0, 1.0) };
+                }
             }
+            normals
         })
-        .collect()
+        .collect();
+    let tex_coords = tri_vertices.iter()
+        .map(|vertices| {
+            let mut coords = [vec2(0.0, 0.0); 3];
+            for (t, v) in coords.iter_mut().zip(vertices) {
+                if let Some(i) = v.tex_coord {
+                    *t = tex_coord(i);
+                }
+            }
+            coords
+        })
+        .collect();
+    Mesh {
+        original_tri_ids: (0..u32(tris.len()).unwrap()).collect(),
+        tris,
+        material_ids,
+        materials,
+        vertex_normals,
+        tex_coords,
+    }
+}
+
+/// Indices of the attributes of a polygon vertex in an OBJ file.
+#[derive(Clone, Copy)]
+struct ObjVertex {
+    position: usize,
+    tex_coord: Option<usize>,
+    normal: Option<usize>,
+}
+
+impl ObjVertex {
+    fn new(position: usize, tex_coord: Option<usize>, normal: Option<usize>) -> Self {
+        ObjVertex {
+            position,
+            tex_coord,
+            normal,
+        }
+    }
+}
+
+/// Compute a normal for every position by averaging the normals of the triangles using it,
+/// weighted by the angle of the triangle at that vertex.
+fn angle_weighted_normals(num_positions: usize,
+                          tris: &[Tri],
+                          tri_vertices: &[[ObjVertex; 3]])
+                          -> Vec<Vector3<f32>> {
+    let mut normals = vec![vec3(0.0, 0.0, 0.0); num_positions];
+    for (tri, vertices) in tris.iter().zip(tri_vertices) {
+        let n = tri.normal();
+        if !n.x.is_finite() {
+            // Degenerate triangles have no meaningful normal.
+            continue;
+        }
+        let corners = [(tri.a, tri.b, tri.c), (tri.b, tri.c, tri.a), (tri.c, tri.a, tri.b)];
+        for (&(p, q, r), v) in corners.iter().zip(vertices) {
+            let cos_angle = (q - p).normalize().dot((r - p).normalize());
+            let angle = cos_angle.max(-1.0).min(1.0).acos();
+            if angle.is_finite() {
+                normals[v.position] += n * angle;
+            }
+        }
+    }
+    for n in &mut normals {
+        *n = n.normalize();
+    }
+    normals
+}
+
+/// Load the materials from all `mtllib`s referenced by the OBJ file at `path`, with their
+/// textures. The first material is the default material.
+/// Also returns the index of every material by name.
+fn read_material_libraries(path: &Path,
+                           obj: &RawObj,
+                           wrap: Wrap)
+                           -> (Vec<Material>, HashMap<String, u32>) {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut materials = vec![Material::default()];
+    let mut material_names = HashMap::new();
+    // Materials often share textures, so every file is only loaded once.
+    let mut textures: HashMap<String, Option<Arc<Texture>>> = HashMap::new();
+    let mut load_texture = |file: &str| {
+        textures.entry(file.to_string())
+            .or_insert_with(|| {
+                let texture_path = dir.join(file);
+                match Texture::load(&texture_path, wrap) {
+                    Ok(texture) => Some(Arc::new(texture)),
+                    Err(e) => {
+                        println!("can't load texture {}: {}", texture_path.display(), e);
+                        None
+                    }
+                }
+            })
+            .clone()
+    };
+    for lib in &obj.material_libraries {
+        let lib_path = dir.join(lib);
+        let file = match File::open(&lib_path) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("can't open material library {}: {}", lib_path.display(), e);
+                continue;
+            }
+        };
+        // Like a missing library, a malformed one leaves its materials at the default.
+        let mtl = match raw::parse_mtl(BufReader::new(file)) {
+            Ok(mtl) => mtl,
+            Err(e) => {
+                println!("can't parse material library {}: {}", lib_path.display(), e);
+                continue;
+            }
+        };
+        // Sort by name so that material IDs don't depend on hash map order.
+        let mut names: Vec<&String> = mtl.materials.keys().collect();
+        names.sort();
+        for name in names {
+            material_names.insert(name.clone(), u32(materials.len()).unwrap());
+            materials.push(Material::from_mtl(name, &mtl.materials[name], &mut load_texture));
+        }
+    }
+    (materials, material_names)
 }
@@ -0,0 +1,86 @@
+use cast::{f32, i64, usize};
+use cgmath::{Vector2, Vector3, vec3};
+use image::{self, ImageError, ImageResult};
+use std::path::Path;
+
+/// How texture coordinates outside of [0, 1] are mapped into the texture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+    /// Repeat, but every other copy is mirrored.
+    Mirror,
+}
+
+impl Wrap {
+    /// Map a texel index into [0, n).
+    fn apply(&self, i: i64, n: i64) -> i64 {
+        match *self {
+            Wrap::Repeat => ((i % n) + n) % n,
+            Wrap::Clamp => i.max(0).min(n - 1),
+            Wrap::Mirror => {
+                let m = ((i % (2 * n)) + 2 * n) % (2 * n);
+                if m < n { m } else { 2 * n - 1 - m }
+            }
+        }
+    }
+}
+
+/// An RGB image texture with bilinear filtering.
+pub struct Texture {
+    width: u32,
+    height: u32,
+    /// Linear RGB values, row by row, top to bottom.
+    texels: Vec<Vector3<f32>>,
+    wrap: Wrap,
+}
+
+impl Texture {
+    /// Load an 8-bit color texture. The texels are assumed to be sRGB encoded.
+    /// Images without any texels are rejected.
+    pub fn load(path: &Path, wrap: Wrap) -> ImageResult<Texture> {
+        let img = image::open(path)?.to_rgb();
+        let (width, height) = img.dimensions();
+        if width == 0 || height == 0 {
+            return Err(ImageError::DimensionError);
+        }
+        let decode = |c: u8| srgb_decode(f32(c) / 255.0);
+        let texels = img.pixels()
+            .map(|px| vec3(decode(px.data[0]), decode(px.data[1]), decode(px.data[2])))
+            .collect();
+        Ok(Texture {
+               width,
+               height,
+               texels,
+               wrap,
+           })
+    }
+
+    /// Look up the texture at the given texture coordinates, where (0, 0) is the lower left and
+    /// (1, 1) the upper right corner of the image.
+    pub fn lookup(&self, uv: Vector2<f32>) -> Vector3<f32> {
+        // Texel centers are at half-integer coordinates.
+        let x = uv.x * f32(self.width) - 0.5;
+        let y = (1.0 - uv.y) * f32(self.height) - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (i64(x0).unwrap_or(0), i64(y0).unwrap_or(0));
+        let texel = |x: i64, y: i64| {
+            let x = self.wrap.apply(x, i64(self.width));
+            let y = self.wrap.apply(y, i64(self.height));
+            self.texels[usize(y * i64(self.width) + x).unwrap()]
+        };
+        let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1, y0) * fx;
+        let bottom = texel(x0, y0 + 1) * (1.0 - fx) + texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+/// The inverse of the sRGB transfer function.
+fn srgb_decode(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}